@@ -1,40 +1,201 @@
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::format;
 
+use crate::cpu::{rdtsc, CPU_FREQ_MHZ};
 use crate::ethernet::{EthernetAddress, EthernetFrame, Ethertype};
 use crate::ip::Ipv4Addr;
 
-use crate::net::{FromBuffer, NetworkDevice, PacketBuffer, ToBuffer, PACKET_BUFFER_SIZE};
-use crate::spinlock::Spinlock;
+use crate::net::{FromBuffer, NetworkDevice, PacketBuffer, ToBuffer};
 
-/// ARP Cache.
-static ARP_CACHE: Spinlock<ArpCache> = Spinlock::<ArpCache>::new(ArpCache(BTreeMap::new()));
+/// How long a resolved entry is trusted before it's treated as stale and
+/// must be re-resolved. A mapping is only refreshed when an ARP packet from
+/// that peer arrives (see `reply`); there's no link-down notice, so this
+/// bounds how long a cache can hold onto a mapping the peer may have changed
+/// underneath us.
+const ENTRY_TTL_SECS: u64 = 60;
 
-pub struct ArpCache(BTreeMap<Ipv4Addr, EthernetAddress>);
+/// Minimum spacing between outbound requests for the same target address,
+/// so a destination that never answers can't be resolved against on every
+/// packet sent to it.
+const REQUEST_INTERVAL_SECS: u64 = 1;
+
+/// Packets held per destination while its hardware address is being
+/// resolved. Bounded so a single unreachable host can't pin down unbounded
+/// memory; the oldest queued packet for that destination is dropped to make
+/// room for a new one.
+const MAX_QUEUED_PER_ADDRESS: usize = 4;
+
+/// Size of a serialized `ArpPacket`, per `ArpPacket::size`.
+const ARP_PACKET_LEN: usize = 28;
+/// Size of a serialized `EthernetFrame` header, per `EthernetFrame::size`.
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// A resolved mapping, aged from the `rdtsc()` reading it was inserted at.
+struct ArpEntry {
+    hardware_address: EthernetAddress,
+    created: u64,
+}
+
+pub struct ArpCache {
+    entries: BTreeMap<Ipv4Addr, ArpEntry>,
+    /// `rdtsc()` reading of the last `resolve` request sent for each target
+    /// address, for rate-limiting.
+    last_request: BTreeMap<Ipv4Addr, u64>,
+    /// Outgoing packets parked by `send_or_queue` awaiting resolution of
+    /// their destination, up to `MAX_QUEUED_PER_ADDRESS` each.
+    pending: BTreeMap<Ipv4Addr, VecDeque<PacketBuffer>>,
+}
 
 impl ArpCache {
-    /// Return the hardware address, if it exists in the cache.
-    pub fn hardware_address(protocol_address: &Ipv4Addr) -> Option<EthernetAddress> {
-        let cache = ARP_CACHE.lock();
-        let result = cache.0.get(protocol_address).copied();
-        result
+    pub const fn new() -> ArpCache {
+        ArpCache {
+            entries: BTreeMap::new(),
+            last_request: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        }
     }
 
-    /// Add a new entry from an ARP reply.
-    pub fn reply(arp_packet: ArpPacket) {
+    /// Return the hardware address, if a non-stale entry exists in the
+    /// cache. A stale entry is evicted rather than just ignored, so it
+    /// doesn't linger in the map. Eviction doesn't touch `resolve`'s rate
+    /// limit, which is tracked separately per address.
+    pub fn hardware_address(&mut self, protocol_address: &Ipv4Addr) -> Option<EthernetAddress> {
+        let entry = self.entries.get(protocol_address)?;
+        let ttl_ticks = ENTRY_TTL_SECS * CPU_FREQ_MHZ * 1_000_000;
+        if rdtsc().saturating_sub(entry.created) >= ttl_ticks {
+            self.entries.remove(protocol_address);
+            return None;
+        }
+        Some(entry.hardware_address)
+    }
+
+    /// Learn the sender's mapping from an incoming ARP request or reply -
+    /// `spa`/`sha` identify the sender in both cases, so a request tells us
+    /// just as much about its sender as a reply does, even though it's not
+    /// itself the answer to anything we asked. Flushes any packets queued
+    /// for this address by `send_or_queue`, rewriting each one's Ethernet
+    /// destination to the newly learned address before sending it on.
+    pub fn reply(&mut self, arp_packet: ArpPacket, device: &mut Box<dyn NetworkDevice>) {
         match arp_packet.oper {
-            Operation::Request | Operation::Unknown => return,
-            Operation::Reply => (),
+            Operation::Unknown => return,
+            Operation::Request | Operation::Reply => (),
         }
 
-        let mut cache = ARP_CACHE.lock();
-        cache.0.insert(arp_packet.spa, arp_packet.sha);
+        self.entries.insert(
+            arp_packet.spa,
+            ArpEntry {
+                hardware_address: arp_packet.sha,
+                created: rdtsc(),
+            },
+        );
+
+        let queue = match self.pending.remove(&arp_packet.spa) {
+            Some(x) => x,
+            None => return,
+        };
+        for mut buffer in queue {
+            buffer.rewrite_front(&EthernetFrame::new(
+                arp_packet.sha,
+                device.hardware_address(),
+                Ethertype::IPV4,
+            ));
+            let _ = device.send(buffer);
+        }
+    }
+
+    /// Send `buffer` now if `destination`'s hardware address is already
+    /// cached, otherwise park it and (re-)fire an ARP request.
+    ///
+    /// `buffer` must already have an `EthernetFrame` header serialized onto
+    /// it as the last step (as `send_ip_packet` does); its destination is
+    /// overwritten once the real one is known, whether that's immediately or
+    /// after `reply` drains the queue.
+    pub fn send_or_queue(
+        &mut self,
+        mut buffer: PacketBuffer,
+        destination: Ipv4Addr,
+        device: &mut Box<dyn NetworkDevice>,
+    ) {
+        if let Some(hardware_address) = self.hardware_address(&destination) {
+            buffer.rewrite_front(&EthernetFrame::new(
+                hardware_address,
+                device.hardware_address(),
+                Ethertype::IPV4,
+            ));
+            let _ = device.send(buffer);
+            return;
+        }
+
+        let queue = self.pending.entry(destination).or_insert_with(VecDeque::new);
+        if queue.len() >= MAX_QUEUED_PER_ADDRESS {
+            queue.pop_front();
+        }
+        queue.push_back(buffer);
+
+        self.resolve(&destination, device);
+    }
+
+    /// Broadcast a gratuitous ARP announcing this device's own address
+    /// mapping - `spa == tpa == device.protocol_address()` and a zeroed
+    /// `tha`, the standard wire form - so peers that already cached our old
+    /// mapping (e.g. after a DHCP rebind onto a new address) refresh it
+    /// without waiting to ask.
+    pub fn announce(&mut self, device: &mut Box<dyn NetworkDevice>) {
+        let protocol_address = device.protocol_address();
+        let broadcast_hardware_address =
+            EthernetAddress::from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let zero_hardware_address = EthernetAddress::from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let announcement = ArpPacket {
+            htype: HardwareType::Ethernet,
+            ptype: ProtocolType::Ipv4,
+            hlen: 6,
+            plen: 4,
+            oper: Operation::Request,
+            sha: device.hardware_address(),
+            spa: protocol_address,
+            tha: zero_hardware_address,
+            tpa: protocol_address,
+        };
+        let ethernet_frame = EthernetFrame::new(
+            broadcast_hardware_address,
+            device.hardware_address(),
+            Ethertype::ARP,
+        );
+
+        let len = ARP_PACKET_LEN + ETHERNET_HEADER_LEN;
+        match device.transmit(len) {
+            Some(mut packet_buffer) => {
+                packet_buffer.serialize(&announcement);
+                packet_buffer.serialize(&ethernet_frame);
+                // Dropping `packet_buffer` hands the now-fully-written frame
+                // to the device for transmission.
+            }
+            None => {
+                // No free descriptor, or this device stack can't support a
+                // loaned buffer at all (e.g. `FaultInjectingDevice` always
+                // refuses) - fall back to the heap-allocated path rather
+                // than silently dropping the announcement.
+                let mut packet_buffer = PacketBuffer::new(len);
+                packet_buffer.serialize(&announcement);
+                packet_buffer.serialize(&ethernet_frame);
+                let _ = device.send(packet_buffer);
+            }
+        }
     }
 
-    /// Send a request to resolve a hardware address.
-    pub fn resolve(protocol_address: &Ipv4Addr, device: &mut Box<dyn NetworkDevice>) {
-        let mut packet_buffer = PacketBuffer::new(PACKET_BUFFER_SIZE);
+    /// Send a request to resolve a hardware address, unless one was already
+    /// sent for this address within the last `REQUEST_INTERVAL_SECS`.
+    pub fn resolve(&mut self, protocol_address: &Ipv4Addr, device: &mut Box<dyn NetworkDevice>) {
+        let now = rdtsc();
+        let interval_ticks = REQUEST_INTERVAL_SECS * CPU_FREQ_MHZ * 1_000_000;
+        if let Some(&last) = self.last_request.get(protocol_address) {
+            if now.saturating_sub(last) < interval_ticks {
+                return;
+            }
+        }
+        self.last_request.insert(*protocol_address, now);
 
         let broadcast_hardware_address =
             EthernetAddress::from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
@@ -49,16 +210,35 @@ impl ArpCache {
             tha: broadcast_hardware_address,
             tpa: *protocol_address,
         };
-        packet_buffer.serialize(&arp_request);
-
         let ethernet_frame = EthernetFrame::new(
             broadcast_hardware_address,
             device.hardware_address(),
             Ethertype::ARP,
         );
-        packet_buffer.serialize(&ethernet_frame);
 
-        device.send(packet_buffer);
+        // An ARP request is always exactly an `ArpPacket` plus an
+        // `EthernetFrame` header - small and fixed-size enough to borrow a
+        // transmit descriptor's buffer directly rather than heap-allocating
+        // and copying into it, when the device stack supports it.
+        let len = ARP_PACKET_LEN + ETHERNET_HEADER_LEN;
+        match device.transmit(len) {
+            Some(mut packet_buffer) => {
+                packet_buffer.serialize(&arp_request);
+                packet_buffer.serialize(&ethernet_frame);
+                // Dropping `packet_buffer` hands the now-fully-written frame
+                // to the device for transmission.
+            }
+            None => {
+                // No free descriptor, or this device stack can't support a
+                // loaned buffer at all (e.g. `FaultInjectingDevice` always
+                // refuses) - fall back to the heap-allocated path rather
+                // than silently dropping the request.
+                let mut packet_buffer = PacketBuffer::new(len);
+                packet_buffer.serialize(&arp_request);
+                packet_buffer.serialize(&ethernet_frame);
+                let _ = device.send(packet_buffer);
+            }
+        }
     }
 }
 
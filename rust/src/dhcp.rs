@@ -0,0 +1,498 @@
+use alloc::vec::Vec;
+
+use crate::cpu::{rdtsc, CPU_FREQ_MHZ};
+use crate::ethernet::EthernetAddress;
+use crate::ip::Ipv4Addr;
+use crate::packet_buffer::{FromBuffer, ToBuffer};
+
+/// Well-known UDP ports a DHCPv4 client and server exchange messages on.
+///
+/// RFC2131 Section 4.1
+/// https://tools.ietf.org/html/rfc2131
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+const HTYPE_ETHERNET: u8 = 1;
+const HLEN_ETHERNET: u8 = 6;
+
+const OP_BOOTREQUEST: u8 = 1;
+
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+/// BOOTP fixed-format fields (op through the magic cookie), before the
+/// variable-length options field.
+///
+/// RFC951, RFC2131 Section 2
+const FIXED_FIELDS_LEN: usize = 240;
+
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+const OPTION_PAD: u8 = 0;
+
+/// A DHCP message type, carried as option 53.
+///
+/// RFC2131 Section 3.1
+/// https://tools.ietf.org/html/rfc2131
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Ack,
+    Nak,
+    Unknown(u8),
+}
+
+impl MessageType {
+    fn from_byte(b: u8) -> MessageType {
+        match b {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            other => MessageType::Unknown(other),
+        }
+    }
+
+    fn as_byte(&self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Unknown(b) => *b,
+        }
+    }
+}
+
+/// A DHCPv4 message, carried as a BOOTP payload (RFC951) with the option
+/// field extensions from RFC2131.
+#[derive(Debug, Clone)]
+pub struct DhcpPacket {
+    op: u8,
+    transaction_id: u32,
+    client_hardware_address: EthernetAddress,
+    client_address: Ipv4Addr,
+    your_address: Ipv4Addr,
+    message_type: MessageType,
+    requested_address: Option<Ipv4Addr>,
+    server_identifier: Option<Ipv4Addr>,
+    subnet_mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns_servers: Vec<Ipv4Addr>,
+    lease_secs: Option<u32>,
+}
+
+impl DhcpPacket {
+    /// Build a DHCPDISCOVER, broadcast to locate a DHCP server.
+    pub fn discover(transaction_id: u32, client_hardware_address: EthernetAddress) -> DhcpPacket {
+        DhcpPacket {
+            op: OP_BOOTREQUEST,
+            transaction_id,
+            client_hardware_address,
+            client_address: Ipv4Addr::new(0, 0, 0, 0),
+            your_address: Ipv4Addr::new(0, 0, 0, 0),
+            message_type: MessageType::Discover,
+            requested_address: None,
+            server_identifier: None,
+            subnet_mask: None,
+            router: None,
+            dns_servers: Vec::new(),
+            lease_secs: None,
+        }
+    }
+
+    /// Build a DHCPREQUEST accepting `offered_address` from the server
+    /// identified by `server_identifier`.
+    pub fn request(
+        transaction_id: u32,
+        client_hardware_address: EthernetAddress,
+        offered_address: Ipv4Addr,
+        server_identifier: Ipv4Addr,
+    ) -> DhcpPacket {
+        DhcpPacket {
+            op: OP_BOOTREQUEST,
+            transaction_id,
+            client_hardware_address,
+            client_address: Ipv4Addr::new(0, 0, 0, 0),
+            your_address: Ipv4Addr::new(0, 0, 0, 0),
+            message_type: MessageType::Request,
+            requested_address: Some(offered_address),
+            server_identifier: Some(server_identifier),
+            subnet_mask: None,
+            router: None,
+            dns_servers: Vec::new(),
+            lease_secs: None,
+        }
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        self.message_type
+    }
+
+    pub fn transaction_id(&self) -> u32 {
+        self.transaction_id
+    }
+
+    pub fn your_address(&self) -> Ipv4Addr {
+        self.your_address
+    }
+
+    pub fn server_identifier(&self) -> Option<Ipv4Addr> {
+        self.server_identifier
+    }
+
+    /// The configuration this message carries, valid for an OFFER or ACK.
+    /// `None` if the server didn't include a lease time, which every OFFER
+    /// and ACK must.
+    pub fn config(&self) -> Option<DhcpConfig> {
+        Some(DhcpConfig {
+            address: self.your_address,
+            subnet_mask: self.subnet_mask,
+            router: self.router,
+            dns_servers: self.dns_servers.clone(),
+            lease_secs: self.lease_secs?,
+        })
+    }
+
+    fn from_slice(buf: &[u8]) -> Result<DhcpPacket, ()> {
+        if buf.len() < FIXED_FIELDS_LEN || buf[236..240] != MAGIC_COOKIE[..] {
+            return Err(());
+        }
+
+        let op = buf[0];
+        let transaction_id = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let client_address = Ipv4Addr::from_slice(&buf[12..16]);
+        let your_address = Ipv4Addr::from_slice(&buf[16..20]);
+        let client_hardware_address = EthernetAddress::from_slice(&buf[28..34]);
+
+        let mut message_type = MessageType::Unknown(0);
+        let mut requested_address = None;
+        let mut server_identifier = None;
+        let mut subnet_mask = None;
+        let mut router = None;
+        let mut dns_servers = Vec::new();
+        let mut lease_secs = None;
+
+        // Walk the TLV options field (RFC2131 Section 3), stopping at the
+        // "End" option or the end of the buffer, whichever comes first.
+        let mut i = FIXED_FIELDS_LEN;
+        while i < buf.len() && buf[i] != OPTION_END {
+            if buf[i] == OPTION_PAD {
+                i += 1;
+                continue;
+            }
+
+            if i + 1 >= buf.len() {
+                break;
+            }
+            let len = buf[i + 1] as usize;
+            let start = i + 2;
+            let end = start + len;
+            if end > buf.len() {
+                break;
+            }
+            let value = &buf[start..end];
+
+            match buf[i] {
+                OPTION_MESSAGE_TYPE if len >= 1 => message_type = MessageType::from_byte(value[0]),
+                OPTION_REQUESTED_IP if len >= 4 => {
+                    requested_address = Some(Ipv4Addr::from_slice(value))
+                }
+                OPTION_SERVER_IDENTIFIER if len >= 4 => {
+                    server_identifier = Some(Ipv4Addr::from_slice(value))
+                }
+                OPTION_SUBNET_MASK if len >= 4 => subnet_mask = Some(Ipv4Addr::from_slice(value)),
+                OPTION_ROUTER if len >= 4 => router = Some(Ipv4Addr::from_slice(value)),
+                OPTION_DNS_SERVERS => {
+                    dns_servers = value.chunks_exact(4).map(Ipv4Addr::from_slice).collect();
+                }
+                OPTION_LEASE_TIME if len >= 4 => {
+                    lease_secs = Some(u32::from_be_bytes([
+                        value[0], value[1], value[2], value[3],
+                    ]))
+                }
+                _ => (),
+            }
+
+            i = end;
+        }
+
+        Ok(DhcpPacket {
+            op,
+            transaction_id,
+            client_hardware_address,
+            client_address,
+            your_address,
+            message_type,
+            requested_address,
+            server_identifier,
+            subnet_mask,
+            router,
+            dns_servers,
+            lease_secs,
+        })
+    }
+
+    /// Build the TLV options field, sized to carry whichever of the
+    /// message-type/requested-address/server-identifier/parameter-request-
+    /// list/subnet-mask/router/DNS-servers/lease-time options this message
+    /// actually uses, so callers sizing a write buffer from this never
+    /// truncate.
+    fn options(&self) -> Vec<u8> {
+        let mut options = Vec::new();
+
+        options.push(OPTION_MESSAGE_TYPE);
+        options.push(1);
+        options.push(self.message_type.as_byte());
+
+        if let Some(requested_address) = self.requested_address {
+            options.push(OPTION_REQUESTED_IP);
+            options.push(4);
+            options.extend_from_slice(&requested_address.as_bytes());
+        }
+
+        if let Some(server_identifier) = self.server_identifier {
+            options.push(OPTION_SERVER_IDENTIFIER);
+            options.push(4);
+            options.extend_from_slice(&server_identifier.as_bytes());
+        }
+
+        if matches!(
+            self.message_type,
+            MessageType::Discover | MessageType::Request
+        ) {
+            options.push(OPTION_PARAMETER_REQUEST_LIST);
+            options.push(4);
+            options.extend_from_slice(&[
+                OPTION_SUBNET_MASK,
+                OPTION_ROUTER,
+                OPTION_DNS_SERVERS,
+                OPTION_LEASE_TIME,
+            ]);
+        }
+
+        if let Some(subnet_mask) = self.subnet_mask {
+            options.push(OPTION_SUBNET_MASK);
+            options.push(4);
+            options.extend_from_slice(&subnet_mask.as_bytes());
+        }
+
+        if let Some(router) = self.router {
+            options.push(OPTION_ROUTER);
+            options.push(4);
+            options.extend_from_slice(&router.as_bytes());
+        }
+
+        if !self.dns_servers.is_empty() {
+            options.push(OPTION_DNS_SERVERS);
+            options.push((self.dns_servers.len() * 4) as u8);
+            for server in &self.dns_servers {
+                options.extend_from_slice(&server.as_bytes());
+            }
+        }
+
+        if let Some(lease_secs) = self.lease_secs {
+            options.push(OPTION_LEASE_TIME);
+            options.push(4);
+            options.extend_from_slice(&lease_secs.to_be_bytes());
+        }
+
+        options.push(OPTION_END);
+        options
+    }
+}
+
+impl FromBuffer for DhcpPacket {
+    fn from_buffer(buf: &[u8]) -> Result<DhcpPacket, ()> {
+        DhcpPacket::from_slice(buf)
+    }
+
+    fn size(&self) -> usize {
+        FIXED_FIELDS_LEN + self.options().len()
+    }
+}
+
+impl ToBuffer for DhcpPacket {
+    fn to_buffer(&self, buf: &mut [u8]) {
+        let options = self.options();
+
+        buf[0] = self.op;
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = HLEN_ETHERNET;
+        buf[3] = 0; // hops
+        buf[4..8].copy_from_slice(&self.transaction_id.to_be_bytes());
+        buf[8..10].copy_from_slice(&0u16.to_be_bytes()); // secs
+        buf[10..12].copy_from_slice(&0u16.to_be_bytes()); // flags
+        buf[12..16].copy_from_slice(&self.client_address.as_bytes());
+        buf[16..20].copy_from_slice(&self.your_address.as_bytes());
+        buf[20..24].copy_from_slice(&Ipv4Addr::new(0, 0, 0, 0).as_bytes()); // siaddr
+        buf[24..28].copy_from_slice(&Ipv4Addr::new(0, 0, 0, 0).as_bytes()); // giaddr
+        buf[28..34].copy_from_slice(&self.client_hardware_address.as_bytes());
+        buf[34..236].copy_from_slice(&[0u8; 202]); // chaddr padding, sname, file
+        buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+        buf[240..240 + options.len()].copy_from_slice(&options);
+    }
+
+    fn size(&self) -> usize {
+        FIXED_FIELDS_LEN + self.options().len()
+    }
+}
+
+/// A DHCP client's negotiated network configuration.
+#[derive(Debug, Clone)]
+pub struct DhcpConfig {
+    pub address: Ipv4Addr,
+    pub subnet_mask: Option<Ipv4Addr>,
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub lease_secs: u32,
+}
+
+/// What a `DhcpClient` wants the caller to do after handling an inbound
+/// message.
+#[derive(Debug, Clone)]
+pub enum DhcpEvent {
+    /// Nothing to send or report.
+    None,
+    /// Send this DHCPREQUEST.
+    Request(DhcpPacket),
+    /// The handshake completed with this negotiated configuration.
+    Bound(DhcpConfig),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+}
+
+/// Drives the DHCPv4 DISCOVER -> OFFER -> REQUEST -> ACK handshake and
+/// tracks the resulting lease, re-requesting it once it's half expired.
+///
+/// RFC2131
+/// https://tools.ietf.org/html/rfc2131
+pub struct DhcpClient {
+    hardware_address: EthernetAddress,
+    state: State,
+    transaction_id: u32,
+    offered_address: Ipv4Addr,
+    server_identifier: Ipv4Addr,
+    config: Option<DhcpConfig>,
+    /// `rdtsc()` reading when the current lease was granted.
+    lease_start: u64,
+    /// Ticks after `lease_start` at which to renew, RFC2131 Section 4.4.5's
+    /// T1 (50% of the lease).
+    renewal_ticks: u64,
+}
+
+impl DhcpClient {
+    pub fn new(hardware_address: EthernetAddress) -> DhcpClient {
+        DhcpClient {
+            hardware_address,
+            state: State::Init,
+            transaction_id: 0,
+            offered_address: Ipv4Addr::new(0, 0, 0, 0),
+            server_identifier: Ipv4Addr::new(0, 0, 0, 0),
+            config: None,
+            lease_start: 0,
+            renewal_ticks: 0,
+        }
+    }
+
+    /// The negotiated configuration, once bound.
+    pub fn config(&self) -> Option<&DhcpConfig> {
+        self.config.as_ref()
+    }
+
+    /// Build a DHCPDISCOVER to start (or restart) the handshake.
+    ///
+    /// `transaction_id` should be a fresh, unpredictable value per RFC2131;
+    /// callers without a random source can derive one from `rdtsc()`.
+    pub fn discover(&mut self, transaction_id: u32) -> DhcpPacket {
+        self.transaction_id = transaction_id;
+        self.state = State::Selecting;
+        DhcpPacket::discover(transaction_id, self.hardware_address)
+    }
+
+    /// Handle an inbound DHCP message.
+    pub fn handle(&mut self, packet: DhcpPacket) -> DhcpEvent {
+        if packet.transaction_id() != self.transaction_id {
+            return DhcpEvent::None;
+        }
+
+        match (self.state, packet.message_type()) {
+            (State::Selecting, MessageType::Offer) => {
+                let server_identifier = match packet.server_identifier() {
+                    Some(x) => x,
+                    None => return DhcpEvent::None,
+                };
+                self.offered_address = packet.your_address();
+                self.server_identifier = server_identifier;
+                self.state = State::Requesting;
+                DhcpEvent::Request(DhcpPacket::request(
+                    self.transaction_id,
+                    self.hardware_address,
+                    self.offered_address,
+                    self.server_identifier,
+                ))
+            }
+            (State::Requesting, MessageType::Ack) => {
+                let config = match packet.config() {
+                    Some(x) => x,
+                    None => return DhcpEvent::None,
+                };
+                self.lease_start = rdtsc();
+                self.renewal_ticks = (config.lease_secs as u64 / 2) * CPU_FREQ_MHZ * 1_000_000;
+                self.config = Some(config.clone());
+                self.state = State::Bound;
+                DhcpEvent::Bound(config)
+            }
+            (State::Requesting, MessageType::Nak) => {
+                self.state = State::Init;
+                DhcpEvent::None
+            }
+            _ => DhcpEvent::None,
+        }
+    }
+
+    /// Has the lease reached its T1 (50%) renewal point?
+    pub fn needs_renewal(&self) -> bool {
+        self.state == State::Bound
+            && rdtsc().saturating_sub(self.lease_start) >= self.renewal_ticks
+    }
+
+    /// The `rdtsc()` reading at which the lease reaches its T1 renewal
+    /// point, once bound - for a caller that wants to sleep until there's
+    /// DHCP work to do rather than polling `needs_renewal` continuously.
+    pub fn renewal_deadline(&self) -> Option<u64> {
+        if self.state == State::Bound {
+            Some(self.lease_start + self.renewal_ticks)
+        } else {
+            None
+        }
+    }
+
+    /// Build a DHCPREQUEST renewing the current lease.
+    pub fn renew(&mut self, transaction_id: u32) -> DhcpPacket {
+        self.transaction_id = transaction_id;
+        self.state = State::Requesting;
+        DhcpPacket::request(
+            transaction_id,
+            self.hardware_address,
+            self.offered_address,
+            self.server_identifier,
+        )
+    }
+}
@@ -1,5 +1,9 @@
+use alloc::format;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 use crate::ethernet::EthernetAddress;
 use crate::ip::Ipv4Addr;
@@ -7,17 +11,25 @@ use crate::kernel::{cprint, ioapicenable, kalloc};
 use crate::mm::{PhysicalAddress, VirtualAddress, PAGE_SIZE};
 use crate::net::NetworkDevice;
 use crate::packet_buffer::PacketBuffer;
+use crate::packet_fifo::PacketFifo;
 use crate::pci;
+use crate::spinlock::Spinlock;
 
 const IRQ_PIC0: u32 = 0xB;
 
 const EEPROM_DONE: u32 = 0x00000010;
 
+// Bound the RX/TX software FIFOs by both entry count and total bytes so a
+// stalled upper layer or a busy card can't grow them without limit.
+const FIFO_MAX_PACKETS: usize = 256;
+const FIFO_MAX_BYTES: usize = 256 * 2048;
+
 // Device identifiers.
 const VENDOR_ID: u16 = 0x8086; // Intel.
 const DEVICE_ID: u16 = 0x100E; // 82540EM Gigabit Ethernet Controller.
 
 // E1000 device registers.
+#[derive(Clone, Copy)]
 enum DeviceRegister {
     CTRL = 0x00000,
     STATUS = 0x00008,
@@ -25,6 +37,7 @@ enum DeviceRegister {
     ICR = 0x000C0,
     IMS = 0x000D0,
     RCTL = 0x00100,
+    RXCSUM = 0x05000,
     TIPG = 0x00410,
     RDBAL = 0x02800,
     RDBAH = 0x02804,
@@ -64,6 +77,120 @@ enum InterruptMask {
     RXT0 = 1 << 7,
 }
 
+/// Bits set in `ICR`, paired with their `InterruptMask` name, for decoding a
+/// raw interrupt status value during tracing.
+const INTERRUPT_NAMES: &[(u32, &str)] = &[
+    (InterruptMask::TXDW as u32, "TXDW"),
+    (InterruptMask::TXQE as u32, "TXQE"),
+    (InterruptMask::LSC as u32, "LSC"),
+    (InterruptMask::RXSEQ as u32, "RXSEQ"),
+    (InterruptMask::RXDMTO as u32, "RXDMTO"),
+    (InterruptMask::RXO as u32, "RXO"),
+    (InterruptMask::RXT0 as u32, "RXT0"),
+];
+
+impl DeviceRegister {
+    /// This register's name, for tracing MMIO accesses.
+    fn name(&self) -> &'static str {
+        match self {
+            DeviceRegister::CTRL => "CTRL",
+            DeviceRegister::STATUS => "STATUS",
+            DeviceRegister::EERD => "EERD",
+            DeviceRegister::ICR => "ICR",
+            DeviceRegister::IMS => "IMS",
+            DeviceRegister::RCTL => "RCTL",
+            DeviceRegister::RXCSUM => "RXCSUM",
+            DeviceRegister::TIPG => "TIPG",
+            DeviceRegister::RDBAL => "RDBAL",
+            DeviceRegister::RDBAH => "RDBAH",
+            DeviceRegister::RDLEN => "RDLEN",
+            DeviceRegister::RDH => "RDH",
+            DeviceRegister::RDT => "RDT",
+            DeviceRegister::TDFPC => "TDFPC",
+            DeviceRegister::TDBAL => "TDBAL",
+            DeviceRegister::TDBAH => "TDBAH",
+            DeviceRegister::TDLEN => "TDLEN",
+            DeviceRegister::TDH => "TDH",
+            DeviceRegister::TDT => "TDT",
+            DeviceRegister::TCTL => "TCTL",
+            DeviceRegister::GPTC => "GPTC",
+            DeviceRegister::TPT => "TPT",
+            DeviceRegister::RAL => "RAL",
+            DeviceRegister::RAH => "RAH",
+            DeviceRegister::MTA_LOW => "MTA_LOW",
+            DeviceRegister::MTA_HIGH => "MTA_HIGH",
+            DeviceRegister::PBM_START => "PBM_START",
+        }
+    }
+}
+
+/// Trace category: log each MMIO register read/write (name, offset, value).
+pub const TRACE_REGISTERS: u8 = 1 << 0;
+/// Trace category: decode the bits set in `ICR` on every interrupt.
+pub const TRACE_INTERRUPTS: u8 = 1 << 1;
+/// Trace category: hex-dump the first bytes of frames passing through
+/// `send`/`recv`.
+pub const TRACE_PACKETS: u8 = 1 << 2;
+
+/// How many leading bytes of a frame `TRACE_PACKETS` dumps to the console.
+const TRACE_DUMP_LEN: usize = 16;
+
+/// Runtime trace mask, independently toggleable per category - mirrors the
+/// `trace=e1000` / EthernetData trace-flag pattern from the external device
+/// models this driver was developed against.
+static TRACE_MASK: AtomicU8 = AtomicU8::new(0);
+
+/// Set which trace categories are active, as an OR of `TRACE_REGISTERS`,
+/// `TRACE_INTERRUPTS` and `TRACE_PACKETS`. Pass `0` to disable all tracing.
+pub fn set_trace_mask(mask: u8) {
+    TRACE_MASK.store(mask, Ordering::Relaxed);
+}
+
+fn trace_enabled(category: u8) -> bool {
+    TRACE_MASK.load(Ordering::Relaxed) & category != 0
+}
+
+/// Log an MMIO register access if `TRACE_REGISTERS` is set.
+fn trace_register(op: char, r: &DeviceRegister, offset: u32, value: u32) {
+    if !trace_enabled(TRACE_REGISTERS) {
+        return;
+    }
+    unsafe {
+        cprint(format!("e1000: {} {} (0x{:05x}) = 0x{:08x}\0", op, r.name(), offset, value).as_ptr());
+    }
+}
+
+/// Decode and log the bits set in an `ICR` value if `TRACE_INTERRUPTS` is set.
+fn trace_interrupts(mask: u32) {
+    if !trace_enabled(TRACE_INTERRUPTS) {
+        return;
+    }
+    for (bit, name) in INTERRUPT_NAMES {
+        if mask & bit != 0 {
+            unsafe {
+                cprint(format!("e1000: ICR {}\0", name).as_ptr());
+            }
+        }
+    }
+}
+
+/// Hex-dump the first `TRACE_DUMP_LEN` bytes of a frame if `TRACE_PACKETS` is
+/// set.
+fn trace_packet(op: &str, ptr: *const u8, len: usize) {
+    if !trace_enabled(TRACE_PACKETS) {
+        return;
+    }
+    let dump_len = core::cmp::min(len, TRACE_DUMP_LEN);
+    let mut hex = String::new();
+    for i in 0..dump_len {
+        let byte = unsafe { core::ptr::read(ptr.add(i)) };
+        let _ = write!(hex, "{:02x} ", byte);
+    }
+    unsafe {
+        cprint(format!("e1000: {} {} bytes: {}\0", op, len, hex).as_ptr());
+    }
+}
+
 /// The receive descriptor.
 #[repr(C)]
 #[derive(Debug, Default)]
@@ -77,6 +204,18 @@ struct RxDesc {
     special: u16,
 }
 
+/// Receive descriptor STATUS bit set by hardware once a descriptor is ready
+/// for software to read (Descriptor Done).
+const RX_STATUS_DD: u8 = 1 << 0;
+/// STATUS bit set when hardware computed and checked the IP checksum.
+const RX_STATUS_IPCS: u8 = 1 << 6;
+/// STATUS bit set when hardware computed and checked the TCP/UDP checksum.
+const RX_STATUS_TCPCS: u8 = 1 << 5;
+/// ERRORS bit set when the IP checksum hardware computed didn't match.
+const RX_ERROR_IPE: u8 = 1 << 6;
+/// ERRORS bit set when the TCP/UDP checksum hardware computed didn't match.
+const RX_ERROR_TCPE: u8 = 1 << 5;
+
 impl RxDesc {
     fn packet_size(&self) -> u16 {
         self.length
@@ -86,8 +225,30 @@ impl RxDesc {
     fn end_of_packet(&self) -> bool {
         self.status & (1 << 1) > 0
     }
+
+    /// Has hardware finished writing this descriptor's buffer?
+    fn descriptor_done(&self) -> bool {
+        self.status & RX_STATUS_DD > 0
+    }
+
+    /// Did hardware check the IP checksum (and the TCP/UDP checksum, if this
+    /// frame carried one) and find both good?
+    ///
+    /// `false` if hardware didn't attempt an IP checksum at all (e.g. a
+    /// non-IPv4 frame), not just if it found one invalid - upper layers
+    /// should fall back to verifying in software in that case.
+    fn checksums_verified(&self) -> bool {
+        let ip_checked = self.status & RX_STATUS_IPCS != 0;
+        let ip_ok = ip_checked && self.errors & RX_ERROR_IPE == 0;
+        let transport_ok = self.status & RX_STATUS_TCPCS == 0 || self.errors & RX_ERROR_TCPE == 0;
+        ip_ok && transport_ok
+    }
 }
 
+/// Transmit descriptor STATUS bit set by hardware once it has written the
+/// frame out and the descriptor's buffer is free for reuse (Descriptor Done).
+const TX_STATUS_DD: u32 = 1 << 0;
+
 /// The transmit descriptor.
 #[repr(C)]
 #[derive(Debug, Default)]
@@ -97,6 +258,98 @@ struct TxDesc {
     options: [u32; 2],
 }
 
+impl TxDesc {
+    /// Has hardware finished transmitting whatever was last written here?
+    fn descriptor_done(&self) -> bool {
+        self.options[1] & TX_STATUS_DD > 0
+    }
+}
+
+/// Offset, within the ethernet frame, of the start of the IPv4 header.
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Per-frame checksum offload offsets, computed by inspecting the outgoing
+/// frame's headers, and written into a TCP/IP context descriptor ahead of
+/// the data descriptor carrying the frame itself.
+struct ChecksumContext {
+    /// Offset of the start of the IP header to checksum.
+    ipcss: u8,
+    /// Offset, within the frame, to write the computed IP checksum.
+    ipcso: u8,
+    /// Offset of the last byte of the IP header checksum computation.
+    ipcse: u16,
+    /// Offsets of a TCP or UDP header needing its own checksum, and whether
+    /// it's TCP (for the context descriptor's TUCMD field): (tucss, tucso, is_tcp).
+    transport: Option<(u8, u8, bool)>,
+}
+
+impl ChecksumContext {
+    /// Inspect a serialized ethernet frame and work out whether hardware can
+    /// offload its IP/TCP/UDP checksums, and at what offsets.
+    ///
+    /// Returns `None` for anything that isn't an IPv4 frame. The header
+    /// length is taken from the IHL field, so a header with options is
+    /// offloaded too.
+    fn from_frame(frame: &[u8]) -> Option<ChecksumContext> {
+        if frame.len() < ETHERNET_HEADER_LEN + 20 {
+            return None;
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        if ethertype != 0x0800 {
+            return None;
+        }
+
+        let ip_start = ETHERNET_HEADER_LEN;
+        let ihl = (frame[ip_start] & 0x0F) as usize * 4;
+        let transport_start = ip_start + ihl;
+
+        let transport = match frame[ip_start + 9] {
+            0x06 if frame.len() >= transport_start + 20 => {
+                Some((transport_start as u8, (transport_start + 16) as u8, true)) // TCP checksum at offset 16.
+            }
+            0x11 if frame.len() >= transport_start + 8 => {
+                Some((transport_start as u8, (transport_start + 6) as u8, false)) // UDP checksum at offset 6.
+            }
+            _ => None,
+        };
+
+        Some(ChecksumContext {
+            ipcss: ip_start as u8,
+            ipcso: (ip_start + 10) as u8,
+            ipcse: (ip_start + ihl - 1) as u16,
+            transport,
+        })
+    }
+}
+
+/// The TCP/IP context descriptor (DTYP=0x0), sharing the same 16-byte slot
+/// type as `TxDesc` in the transmit ring. Written immediately ahead of a
+/// data descriptor (DTYP=0x1) to carry the header offsets hardware needs to
+/// compute and insert IP/TCP/UDP checksums itself.
+///
+/// Reference: Manual - Section 3.3.3
+#[repr(C)]
+#[derive(Debug, Default)]
+struct TxContextDesc {
+    ipcss: u8,
+    ipcso: u8,
+    ipcse: u16,
+    tucss: u8,
+    tucso: u8,
+    tucse: u16,
+    /// PAYLEN (bits 0:19), DTYP (bits 20:23), TUCMD (bits 24:31).
+    paylen_dtyp_tucmd: u32,
+    /// STA (bits 0:7), POPTS mirrored here purely as ring bookkeeping - real
+    /// hardware doesn't write this back for a context descriptor, so we set
+    /// it ourselves once the descriptor is written (see `hw_send`).
+    sta: u32,
+}
+
+/// POPTS bit requesting IP checksum insertion on the paired data descriptor.
+const TX_POPTS_IXSM: u32 = 1 << 8;
+/// POPTS bit requesting TCP/UDP checksum insertion on the paired data descriptor.
+const TX_POPTS_TXSM: u32 = 1 << 9;
+
 /// A representation of the e1000 family device state.
 pub struct E1000 {
     /// Base address of the memory mapped IO space of the device.
@@ -119,6 +372,12 @@ pub struct E1000 {
 
     /// The next transmit descriptor to be written to.
     tx_idx: u32,
+
+    /// Frames drained from the hardware receive ring, awaiting `recv()`.
+    rx_fifo: Spinlock<PacketFifo>,
+
+    /// Frames awaiting a free transmit descriptor.
+    tx_fifo: Spinlock<PacketFifo>,
 }
 
 impl E1000 {
@@ -146,31 +405,24 @@ impl E1000 {
             rx_idx: 0,
             tx: vec![],
             tx_idx: 0,
+            rx_fifo: Spinlock::new(PacketFifo::new(FIFO_MAX_PACKETS, FIFO_MAX_BYTES)),
+            tx_fifo: Spinlock::new(PacketFifo::new(FIFO_MAX_PACKETS, FIFO_MAX_BYTES)),
         };
 
-        // Enumerate the first four devices on the first PCI bus.
-        // TODO: Move this out to a more generic PCI `probe` routine.
-        let mut target_device: Option<u32> = None;
-        for device in 0..4 {
-            let device_addr: u32 = 0x80000000 | (device << 11);
-
-            // Read the vendor and device id of the current device.
-            let vendor_id = pci::read_vendor_id(device_addr);
-            let device_id = pci::read_device_id(device_addr);
-            if vendor_id == VENDOR_ID && device_id == DEVICE_ID {
-                target_device = Some(device);
-                break;
-            }
-        }
-
-        if target_device.is_none() {
-            cprint(b"failed to locate network device\n\x00".as_ptr());
-            panic!();
-        }
+        // Enumerate the PCI bus and locate our device by (vendor, device) id.
+        let devices = pci::probe();
+        let target_device = pci::find_by_id(&devices, VENDOR_ID, DEVICE_ID)?;
 
         // Configure the device command register and read the MMIO base register.
-        pci::set_bus_master(target_device.unwrap());
-        e1000.mmio_base = pci::read_bar(target_device.unwrap(), 0);
+        target_device.set_bus_master();
+        e1000.mmio_base = match target_device.decode_bar(0) {
+            pci::Bar::Memory32 { base, .. } => base,
+            pci::Bar::Memory64 { base, .. } => base as u32,
+            _ => {
+                cprint(b"unexpected e1000 BAR0 type\n\x00".as_ptr());
+                panic!();
+            }
+        };
 
         // Read the MAC address.
         // TODO: Lock EEPROM.
@@ -262,6 +514,13 @@ impl E1000 {
         rctl |= 3 << 16; // Buffer size (4069 bytes).
         rctl |= 1 << 25; // Buffer size extension.
         self.write_register(DeviceRegister::RCTL, rctl);
+
+        // Ask hardware to validate IP and TCP/UDP checksums for us, surfaced
+        // back to software via RxDesc's STATUS/ERRORS fields.
+        let mut rxcsum: u32 = 0x0;
+        rxcsum |= 1 << 8; // IPOFLD: IP checksum offload enable.
+        rxcsum |= 1 << 9; // TUOFLD: TCP/UDP checksum offload enable.
+        self.write_register(DeviceRegister::RXCSUM, rxcsum);
     }
 
     /// Transmission initialization.
@@ -280,6 +539,10 @@ impl E1000 {
         for desc in self.tx.iter_mut() {
             let buf = kalloc() as *mut u8;
             desc.addr = PhysicalAddress::from_virtual(buf as u64);
+            // Mark every descriptor done up front so the first frame queued
+            // into each slot sees it as free, matching the write-back state
+            // hardware leaves behind once a real transmission completes.
+            desc.options[1] = TX_STATUS_DD;
         }
         self.tx_idx = 1;
 
@@ -317,12 +580,17 @@ impl E1000 {
 
     /// Read a device register.
     unsafe fn read_register(&self, r: DeviceRegister) -> u32 {
-        return core::ptr::read_volatile((self.mmio_base + r as u32) as *const u32);
+        let offset = r as u32;
+        let value = core::ptr::read_volatile((self.mmio_base + offset) as *const u32);
+        trace_register('R', &r, offset, value);
+        value
     }
 
     /// Write a device register.
     unsafe fn write_register(&self, r: DeviceRegister, data: u32) {
-        core::ptr::write_volatile((self.mmio_base + r as u32) as *mut u32, data);
+        let offset = r as u32;
+        core::ptr::write_volatile((self.mmio_base + offset) as *mut u32, data);
+        trace_register('W', &r, offset, data);
     }
 
     /// Return the hardware adddress of the network device.
@@ -339,6 +607,165 @@ impl E1000 {
     fn set_protocol_address(&mut self, protocol_address: Ipv4Addr) {
         self.protocol_address = Some(protocol_address);
     }
+
+    /// Drain every completed descriptor off the hardware receive ring into
+    /// `rx_fifo`, so the interrupt handler never blocks waiting on upper
+    /// layers. Stops early if the FIFO fills up; the remaining frames stay
+    /// on the ring until the next drain.
+    fn drain_rx_ring(&mut self) {
+        loop {
+            let idx = self.rx_idx as usize;
+            let desc = &self.rx[idx];
+            if !desc.descriptor_done() || !desc.end_of_packet() {
+                break;
+            }
+
+            // 4 bytes removed for ethernet FCS.
+            let frame_size = (desc.packet_size() - 4) as usize;
+            if !self.rx_fifo.lock().has_space(frame_size) {
+                break;
+            }
+            let addr = desc.addr.to_virtual().0 as *mut u8;
+            let checksums_verified = desc.checksums_verified();
+
+            self.rx_idx += 1;
+            if self.rx_idx == self.rx.len() as u32 {
+                self.rx_idx = 0;
+            }
+
+            let desc_ptr = &mut self.rx[idx] as *mut RxDesc as usize;
+            let rdt_reg = (self.mmio_base + DeviceRegister::RDT as u32) as usize;
+            let mut buf = unsafe {
+                PacketBuffer::new_loaned(addr, frame_size, move || {
+                    // Return the descriptor to hardware: clear its status
+                    // (DD) so a stale completion isn't seen again, then
+                    // advance RDT past it so the card can refill the slot.
+                    (*(desc_ptr as *mut RxDesc)).status = 0;
+                    core::ptr::write_volatile(rdt_reg as *mut u32, idx as u32);
+                })
+            };
+            buf.set_checksum_verified(checksums_verified);
+
+            // Checked above with `has_space`, so this cannot fail.
+            let _ = self.rx_fifo.lock().push(buf, frame_size);
+        }
+    }
+
+    /// How many ring slots `hw_send` will need for `buf`: two if it can
+    /// offload checksums (a context descriptor ahead of the data
+    /// descriptor), one otherwise.
+    fn slots_needed(buf: &PacketBuffer) -> usize {
+        let frame = unsafe { core::slice::from_raw_parts(buf.as_ptr(), buf.len()) };
+        match ChecksumContext::from_frame(frame) {
+            Some(_) => 2,
+            None => 1,
+        }
+    }
+
+    /// Are the next `n` descriptors, starting at `tx_idx`, all free?
+    fn tx_slots_free(&self, n: usize) -> bool {
+        (0..n).all(|i| {
+            let idx = (self.tx_idx as usize + i) % self.tx.len();
+            self.tx[idx].descriptor_done()
+        })
+    }
+
+    /// Push as many queued TX frames onto the hardware ring as there are
+    /// free descriptors for.
+    fn drain_tx_fifo(&mut self) {
+        loop {
+            let slots = match self.tx_fifo.lock().peek() {
+                Some(buf) => Self::slots_needed(buf),
+                None => break,
+            };
+            if !self.tx_slots_free(slots) {
+                break;
+            }
+            let buf = match self.tx_fifo.lock().pop() {
+                Some(buf) => buf,
+                None => break,
+            };
+            self.hw_send(buf);
+        }
+    }
+
+    /// Write a TCP/IP context descriptor into the current ring slot,
+    /// carrying the header offsets hardware needs to compute `ctx`'s
+    /// checksums, then advance past it.
+    ///
+    /// Real hardware only writes DD back for a descriptor that requested it
+    /// (RS), which we never set here - nothing reads this slot's contents
+    /// again, so we mark it done ourselves so the ring-availability check
+    /// sees it as reusable once we've moved past it.
+    fn write_context_descriptor(&mut self, ctx: &ChecksumContext) {
+        let idx = self.tx_idx as usize;
+        let context_desc = &mut self.tx[idx] as *mut TxDesc as *mut TxContextDesc;
+        let (tucss, tucso, is_tcp) = ctx.transport.unwrap_or((0, 0, false));
+        unsafe {
+            (*context_desc).ipcss = ctx.ipcss;
+            (*context_desc).ipcso = ctx.ipcso;
+            (*context_desc).ipcse = ctx.ipcse;
+            (*context_desc).tucss = tucss;
+            (*context_desc).tucso = tucso;
+            (*context_desc).tucse = 0; // Checksum runs to the end of the packet.
+            let tucmd = is_tcp as u32;
+            (*context_desc).paylen_dtyp_tucmd = tucmd << 24;
+            (*context_desc).sta = TX_STATUS_DD;
+        }
+
+        self.tx_idx += 1;
+        if self.tx_idx as usize == self.tx.len() {
+            self.tx_idx = 0;
+        }
+    }
+
+    /// Write `buf` into the next transmit descriptor and kick the ring,
+    /// first writing a checksum-offload context descriptor ahead of it if
+    /// `buf` carries headers hardware can checksum for us. Callers must
+    /// have already confirmed `Self::slots_needed(&buf)` descriptors,
+    /// starting at `tx_idx`, are free.
+    fn hw_send(&mut self, buf: PacketBuffer) {
+        let frame = unsafe { core::slice::from_raw_parts(buf.as_ptr(), buf.len()) };
+        let context = ChecksumContext::from_frame(frame);
+        if let Some(ctx) = &context {
+            self.write_context_descriptor(ctx);
+        }
+
+        let idx = self.tx_idx as usize;
+        let tx_desc = &mut self.tx[idx];
+
+        // Write the payload into the transmit buffer.
+        let tx_buf = tx_desc.addr.to_virtual().0 as *mut u8;
+        unsafe {
+            core::ptr::copy(buf.as_ptr(), tx_buf, buf.len());
+        }
+
+        // Setup the transmit descriptor. Clear DD first: the card sets it
+        // again once it has written this frame out, which is what the next
+        // caller into this slot checks.
+        tx_desc.options[1] = 0;
+        let size = buf.len() as u32;
+        let dtyp = 1u32 << 0;
+        let dcmd = (1u32 << 0) | (1u32 << 3) | (1u32 << 5);
+        tx_desc.options[0] = size | (dtyp << 20) | (dcmd << 24);
+
+        // Request IP/TCP/UDP checksum insertion using the context just written.
+        if let Some(ctx) = &context {
+            let mut popts = TX_POPTS_IXSM;
+            if ctx.transport.is_some() {
+                popts |= TX_POPTS_TXSM;
+            }
+            tx_desc.options[1] |= popts;
+        }
+
+        self.tx_idx += 1;
+        if self.tx_idx as usize == self.tx.len() {
+            self.tx_idx = 0;
+        }
+        unsafe {
+            self.write_register(DeviceRegister::TDT, self.tx_idx);
+        }
+    }
 }
 
 /// Implement the common network interface.
@@ -356,10 +783,16 @@ impl NetworkDevice for E1000 {
     }
 
     /// Clear the current state of the interrupt register.
+    ///
+    /// Rather than handing frames to the stack inline, this drains the
+    /// hardware rings into the software FIFOs: received frames into
+    /// `rx_fifo` for `recv()` to pop at its own pace, and any TX frames that
+    /// were queued up behind a busy descriptor into freed-up slots.
     fn clear_interrupts(&mut self) {
         // Read the interrupt register and dispatch to the correct handler.
         unsafe {
             let mask = self.read_register(DeviceRegister::ICR);
+            trace_interrupts(mask);
             if mask & InterruptMask::TXDW as u32 != 0 {
                 // cprint(b"e1000: tx descriptor write-back\n\x00".as_ptr());
             } else if mask & InterruptMask::TXQE as u32 != 0 {
@@ -376,62 +809,79 @@ impl NetworkDevice for E1000 {
                 // cprint(b"e1000: rx min threshold\n\x00".as_ptr());
             }
         }
-    }
 
-    /// Send the contents of a PacketBuffer over the wire.
-    fn send(&mut self, buf: PacketBuffer) {
-        let mut tx_desc = &mut self.tx[self.tx_idx as usize];
+        self.drain_rx_ring();
+        self.drain_tx_fifo();
+    }
 
-        // Write the payload into the transmit buffer.
-        let tx_buf = tx_desc.addr.to_virtual().0 as *mut u8;
-        unsafe {
-            core::ptr::copy(buf.as_ptr(), tx_buf, buf.len());
+    /// Borrow the next transmit descriptor's buffer directly, if it's free.
+    ///
+    /// Unlike `send`, a borrowed descriptor can't fall back to `tx_fifo` if
+    /// the ring is busy - there's no frame to queue yet, just a `None` the
+    /// caller is expected to handle (e.g. by using `send` instead, which
+    /// still copies but can queue). Doesn't offer checksum offload: that
+    /// requires inspecting the finished frame to build a context
+    /// descriptor ahead of it (see `hw_send`), which isn't possible before
+    /// the caller has serialized anything into the buffer this returns.
+    fn transmit(&mut self, len: usize) -> Option<PacketBuffer> {
+        self.drain_tx_fifo();
+        if self.tx_fifo.lock().len() != 0 || !self.tx_slots_free(1) {
+            return None;
         }
 
-        // Setup the transmit descriptor.
-        let size = buf.len() as u32;
-        let dtyp = 1u32 << 0;
-        let dcmd = (1u32 << 0) | (1u32 << 3) | (1u32 << 5);
-        tx_desc.options[0] = size | (dtyp << 20) | (dcmd << 24);
+        let idx = self.tx_idx as usize;
+        let tx_buf = self.tx[idx].addr.to_virtual().0 as *mut u8;
 
         self.tx_idx += 1;
         if self.tx_idx as usize == self.tx.len() {
             self.tx_idx = 0;
         }
-        unsafe {
-            self.write_register(DeviceRegister::TDT, self.tx_idx);
-        }
+        let next_tx_idx = self.tx_idx;
+
+        let desc_ptr = &mut self.tx[idx] as *mut TxDesc as usize;
+        let tdt_reg = (self.mmio_base + DeviceRegister::TDT as u32) as usize;
+        Some(unsafe {
+            PacketBuffer::new_loaned(tx_buf, len, move || {
+                let tx_desc = &mut *(desc_ptr as *mut TxDesc);
+                tx_desc.options[1] = 0;
+                let size = len as u32;
+                let dtyp = 1u32 << 0;
+                let dcmd = (1u32 << 0) | (1u32 << 3) | (1u32 << 5);
+                tx_desc.options[0] = size | (dtyp << 20) | (dcmd << 24);
+                core::ptr::write_volatile(tdt_reg as *mut u32, next_tx_idx);
+            })
+        })
     }
 
-    /// Read avaliable packets from the device.
-    /// TODO: Loan PacketBuffer?
-    fn recv(&mut self) -> Option<PacketBuffer> {
-        unsafe {
-            let head = self.read_register(DeviceRegister::RDH);
-            if self.rx_idx == head {
-                // Ring buffer is empty.
-                return None;
-            }
-        }
-
-        let desc = &self.rx[self.rx_idx as usize];
-        if !desc.end_of_packet() {
-            panic!(); // TODO: Handle?
+    /// Queue a PacketBuffer for transmission.
+    ///
+    /// Frames go straight to the wire when there's a free descriptor;
+    /// otherwise they're parked on `tx_fifo` and flushed out as descriptors
+    /// free up (see `drain_tx_fifo`, called from `clear_interrupts`).
+    /// Returns `Err(())` only if `tx_fifo` itself is full.
+    fn send(&mut self, buf: PacketBuffer) -> Result<(), ()> {
+        trace_packet("send", buf.as_ptr(), buf.len());
+        self.drain_tx_fifo();
+
+        if self.tx_fifo.lock().len() == 0 && self.tx_slots_free(Self::slots_needed(&buf)) {
+            self.hw_send(buf);
+            return Ok(());
         }
 
-        self.rx_idx += 1;
-        if self.rx_idx == self.rx.len() as u32 {
-            self.rx_idx = 0;
-        }
+        let size = buf.len();
+        self.tx_fifo.lock().push(buf, size).map_err(|_| ())
+    }
 
-        unsafe {
-            self.write_register(DeviceRegister::RDT, self.rx_idx - 1);
+    /// Pop the oldest frame already drained into `rx_fifo` by
+    /// `clear_interrupts`; never touches the hardware ring directly.
+    fn recv(&mut self) -> Option<PacketBuffer> {
+        let buf = self.rx_fifo.lock().pop();
+        if let Some(buf) = &buf {
+            // `buf` hasn't been `parse`d yet, so `len()` (the parse offset)
+            // would log 0 regardless of the real frame size - same fix
+            // `TracingDevice`/`PcapDevice` needed for the same reason.
+            trace_packet("recv", buf.as_ptr(), buf.remaining().len());
         }
-
-        // 4 bytes removed for ethernet FCS
-        Some(PacketBuffer::new_from_bytes(
-            desc.addr.to_virtual().0 as *const u8,
-            (desc.packet_size() - 4) as usize,
-        ))
+        buf
     }
 }
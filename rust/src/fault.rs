@@ -0,0 +1,121 @@
+use crate::cpu::rdtsc;
+use crate::net::NetworkDevice;
+use crate::packet_buffer::PacketBuffer;
+
+/// Wraps a `NetworkDevice` to exercise retransmission and timeout logic
+/// without needing an actual unreliable link: outgoing frames are randomly
+/// dropped per `drop_percent`, and sends are rate-shaped to at least
+/// `interval_ticks` apart - a frame that arrives sooner is held back and
+/// sent on the next `send`/`recv` call once the interval has elapsed. Only
+/// one frame is held at a time: a frame sent while another is still held
+/// replaces it, and the replaced frame is dropped.
+///
+/// Stacks with `trace::TracingDevice` and `pcap::PcapDevice` - each wrapper
+/// only touches what it's responsible for and forwards everything else to
+/// `inner`.
+pub struct FaultInjectingDevice<D: NetworkDevice> {
+    inner: D,
+    drop_percent: u8,
+    interval_ticks: u64,
+    next_allowed: u64,
+    pending: Option<PacketBuffer>,
+    rng_state: u32,
+}
+
+impl<D: NetworkDevice> FaultInjectingDevice<D> {
+    /// `drop_percent` is clamped to `0..=100`. `interval_ticks` is the
+    /// minimum spacing, in `rdtsc()` ticks, enforced between two frames
+    /// actually reaching `inner`.
+    pub fn new(inner: D, drop_percent: u8, interval_ticks: u64) -> FaultInjectingDevice<D> {
+        FaultInjectingDevice {
+            inner,
+            drop_percent: core::cmp::min(drop_percent, 100),
+            interval_ticks,
+            next_allowed: 0,
+            pending: None,
+            rng_state: rdtsc() as u32 | 1,
+        }
+    }
+
+    /// xorshift32, seeded from the cycle counter - good enough for picking
+    /// which frames to drop, not for anything security sensitive.
+    fn next_random(&mut self) -> u32 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 17;
+        self.rng_state ^= self.rng_state << 5;
+        self.rng_state
+    }
+
+    fn should_drop(&mut self) -> bool {
+        self.drop_percent > 0 && self.next_random() % 100 < self.drop_percent as u32
+    }
+
+    /// If a frame is being held back for rate-shaping, send it once the
+    /// interval since the last send has elapsed.
+    fn flush_pending(&mut self) {
+        let buf = match self.pending.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+
+        if rdtsc() < self.next_allowed {
+            self.pending = Some(buf);
+            return;
+        }
+
+        self.next_allowed = rdtsc() + self.interval_ticks;
+        let _ = self.inner.send(buf);
+    }
+}
+
+impl<D: NetworkDevice> NetworkDevice for FaultInjectingDevice<D> {
+    fn hardware_address(&self) -> crate::ethernet::EthernetAddress {
+        self.inner.hardware_address()
+    }
+
+    fn protocol_address(&self) -> crate::ip::Ipv4Addr {
+        self.inner.protocol_address()
+    }
+
+    fn set_protocol_address(&mut self, protocol_address: crate::ip::Ipv4Addr) {
+        self.inner.set_protocol_address(protocol_address);
+    }
+
+    fn clear_interrupts(&mut self) {
+        self.inner.clear_interrupts();
+    }
+
+    /// Always refuses, rather than forwarding to `inner`. Drop/rate-shaping
+    /// need `&mut self` to update `rng_state`/`next_allowed`/`pending`, but
+    /// the returned buffer's `reclaim` runs later, as a `'static` closure
+    /// with no access back into this device - there's no way to apply fault
+    /// injection to a frame that reaches hardware on drop instead of through
+    /// `send`. Callers that get `None` here are expected to fall back to
+    /// building an owned buffer and going through `send`, which still
+    /// applies every fault.
+    fn transmit(&mut self, _len: usize) -> Option<PacketBuffer> {
+        None
+    }
+
+    fn send(&mut self, buf: PacketBuffer) -> Result<(), ()> {
+        self.flush_pending();
+
+        if self.should_drop() {
+            return Ok(());
+        }
+
+        let now = rdtsc();
+        if now < self.next_allowed || self.pending.is_some() {
+            self.pending = Some(buf);
+            return Ok(());
+        }
+
+        self.next_allowed = now + self.interval_ticks;
+        self.inner.send(buf)
+    }
+
+    fn recv(&mut self) -> Option<PacketBuffer> {
+        self.flush_pending();
+        self.inner.recv()
+    }
+}
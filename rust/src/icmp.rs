@@ -1,112 +1,177 @@
-use alloc::format;
 use alloc::vec;
 use alloc::vec::Vec;
 
 use crate::packet_buffer::{FromBuffer, ToBuffer};
 
-/// Represents an ICMP echo packet.
+/// ICMP type numbers used by this stack.
+///
+/// RFC792 Section "Summary of Message Types"
+/// https://tools.ietf.org/html/rfc792
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+
+/// Destination Unreachable code: the datagram's protocol (e.g. UDP) had no
+/// listener bound to the destination port.
+///
+/// RFC792 Section "Destination Unreachable Message"
+/// https://tools.ietf.org/html/rfc792
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+
+/// Time Exceeded code: a fragmented datagram's reassembly timer ran out
+/// before every fragment arrived.
+///
+/// RFC792 Section "Time Exceeded Message"
+/// https://tools.ietf.org/html/rfc792
+pub const CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+/// How many bytes of the original datagram a Destination Unreachable or Time
+/// Exceeded message carries back to the sender: its IP header plus the
+/// first 8 bytes of its payload, per RFC792.
+const ERROR_QUOTE_LEN: usize = 28;
+
+/// Represents an ICMP message.
+///
+/// Every ICMP message shares this layout, so rather than a variant per
+/// message kind we keep the generic header fields and let `icmp_type`/`code`
+/// say what the message is; `rest_of_header` holds whatever that kind of
+/// message stores there (e.g. echo identifier/sequence, unused for
+/// Destination Unreachable).
+///
+/// RFC792
+/// https://tools.ietf.org/html/rfc792
 #[derive(Debug, Clone)]
-pub struct IcmpEchoMessage {
-    pub r#type: Type,
-    code: u8,
+pub struct IcmpPacket {
+    pub icmp_type: u8,
+    pub code: u8,
     checksum: u16,
-    identifier: u16,
-    sequence_number: u16,
-    data: Vec<u8>,
+    pub rest_of_header: u32,
+    payload: Vec<u8>,
 }
 
-impl IcmpEchoMessage {
-    /// Build a new echo response from a request.
-    pub fn from_request(req: IcmpEchoMessage) -> IcmpEchoMessage {
-        IcmpEchoMessage {
-            r#type: Type::EchoReply,
+impl IcmpPacket {
+    /// Build an Echo Request (ping), RFC792's combination of identifier and
+    /// sequence number packed into `rest_of_header`.
+    pub fn echo_request(identifier: u16, sequence_number: u16, payload: Vec<u8>) -> IcmpPacket {
+        let rest_of_header = ((identifier as u32) << 16) | sequence_number as u32;
+        let mut packet = IcmpPacket {
+            icmp_type: TYPE_ECHO_REQUEST,
             code: 0,
-            checksum: req.checksum,
-            identifier: req.identifier,
-            sequence_number: req.sequence_number,
-            data: req.data,
-        }
+            checksum: 0,
+            rest_of_header,
+            payload,
+        };
+        packet.checksum = packet.calculate_checksum();
+        packet
     }
-}
 
-/// Represents an ICMP packet.
-#[derive(Debug, Clone)]
-pub enum IcmpPacket {
-    EchoMessage(IcmpEchoMessage),
-}
+    /// Turn an inbound Echo Request into the Echo Reply that answers it,
+    /// preserving the identifier, sequence number and payload and
+    /// recomputing the checksum.
+    pub fn echo_reply(request: &IcmpPacket) -> IcmpPacket {
+        let mut packet = IcmpPacket {
+            icmp_type: TYPE_ECHO_REPLY,
+            code: 0,
+            checksum: 0,
+            rest_of_header: request.rest_of_header,
+            payload: request.payload.clone(),
+        };
+        packet.checksum = packet.calculate_checksum();
+        packet
+    }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-pub enum Type {
-    EchoReply,
-    Reserved,
-    DestinationUnreachable,
-    SourceQuench,
-    RedirectMessage,
-    EchoRequest,
-    RouterAdvertisement,
-    RouterSolicitation,
-    TimeExceeded,
-    ParameterProblem,
-    Timestamp,
-    TimestampReply,
-    InformationRequest,
-    InformationReply,
-    AddressMaskRequest,
-    AddressMaskReply,
-    Traceroute,
-    ExtendedEchoRequest,
-    ExtendedEchoReply,
-    Unknown,
-}
+    /// Build a Destination Unreachable message reporting why
+    /// `original_datagram` (the IP datagram that triggered this, starting at
+    /// its IP header) couldn't be delivered.
+    pub fn destination_unreachable(code: u8, original_datagram: &[u8]) -> IcmpPacket {
+        IcmpPacket::error(TYPE_DESTINATION_UNREACHABLE, code, original_datagram)
+    }
 
-impl Type {
-    pub fn from_slice(buf: &[u8]) -> Type {
-        match buf[0] {
-            0x00u8 => Type::EchoReply,
-            0x01u8 => Type::Reserved,
-            0x02u8 => Type::Reserved,
-            0x03u8 => Type::DestinationUnreachable,
-            0x04u8 => Type::SourceQuench,
-            0x08u8 => Type::EchoRequest,
-            _ => Type::Unknown,
-        }
+    /// Build a Time Exceeded message reporting that `original_datagram` (the
+    /// IP datagram that triggered this, starting at its IP header) wasn't
+    /// fully reassembled or forwarded before its timer ran out.
+    pub fn time_exceeded(code: u8, original_datagram: &[u8]) -> IcmpPacket {
+        IcmpPacket::error(TYPE_TIME_EXCEEDED, code, original_datagram)
     }
 
-    pub fn as_bytes(&self) -> u8 {
-        match self {
-            Type::EchoReply => 0x00u8,
-            Type::Reserved => 0x01u8,
-            Type::Reserved => 0x02u8,
-            Type::DestinationUnreachable => 0x03u8,
-            Type::SourceQuench => 0x04u8,
-            Type::EchoRequest => 0x08u8,
-            Type::Unknown => panic!(),
-            _ => panic!(),
-        }
+    /// Shared constructor for Destination Unreachable and Time Exceeded:
+    /// both share the same "header plus quoted datagram" layout, differing
+    /// only in `icmp_type` and `code`.
+    fn error(icmp_type: u8, code: u8, original_datagram: &[u8]) -> IcmpPacket {
+        let len = core::cmp::min(original_datagram.len(), ERROR_QUOTE_LEN);
+        let mut packet = IcmpPacket {
+            icmp_type,
+            code,
+            checksum: 0,
+            rest_of_header: 0,
+            payload: original_datagram[..len].to_vec(),
+        };
+        packet.checksum = packet.calculate_checksum();
+        packet
     }
-}
 
-impl IcmpPacket {
-    pub fn from_slice(buf: &[u8]) -> IcmpPacket {
-        let r#type = Type::from_slice(&buf[0..]);
-        match r#type {
-            Type::EchoReply | Type::EchoRequest => IcmpPacket::EchoMessage(IcmpEchoMessage {
-                r#type: r#type,
-                code: buf[1],
-                checksum: u16::from_be_bytes([buf[2], buf[3]]),
-                identifier: u16::from_be_bytes([buf[4], buf[5]]),
-                sequence_number: u16::from_be_bytes([buf[6], buf[7]]),
-                data: buf[8..].to_vec(),
-            }),
-            _ => panic!(),
+    /// Is this an Echo Request?
+    pub fn is_echo_request(&self) -> bool {
+        self.icmp_type == TYPE_ECHO_REQUEST
+    }
+
+    /// Parse `buf` as an ICMP message, rejecting it if it's shorter than the
+    /// fixed 8-byte header or the checksum doesn't cover it correctly rather
+    /// than trusting a corrupt wire message.
+    fn from_slice(buf: &[u8]) -> Result<IcmpPacket, ()> {
+        if buf.len() < 8 {
+            return Err(());
         }
+
+        // Over the message as received (embedded checksum included), a
+        // correct checksum's one's-complement sum is all one bits.
+        if IcmpPacket::checksum(buf) != 0 {
+            return Err(());
+        }
+
+        Ok(IcmpPacket {
+            icmp_type: buf[0],
+            code: buf[1],
+            checksum: u16::from_be_bytes([buf[2], buf[3]]),
+            rest_of_header: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            payload: buf[8..].to_vec(),
+        })
     }
 
-    fn calculate_checksum(buf: &[u8]) -> u16 {
+    /// Write the header and payload to `buf`, using `checksum` for the
+    /// checksum field - so the same code can write the zeroed buffer used
+    /// to calculate the checksum, and the final buffer with it filled in.
+    fn write(&self, buf: &mut [u8], checksum: u16) {
+        buf[0] = self.icmp_type;
+        buf[1] = self.code;
+        buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.rest_of_header.to_be_bytes());
+        buf[8..8 + self.payload.len()].copy_from_slice(&self.payload[..]);
+    }
+
+    fn calculate_checksum(&self) -> u16 {
+        let mut bytes = vec![0u8; 8 + self.payload.len()];
+        self.write(&mut bytes, 0);
+        IcmpPacket::checksum(&bytes)
+    }
+
+    /// Calculates the checksum for a slice of bytes.
+    ///
+    /// A trailing odd byte (an odd-length echo payload, say) is padded as
+    /// the high byte of a final 16-bit word, per the one's-complement sum
+    /// RFC792 specifies.
+    ///
+    /// RFC792
+    /// https://tools.ietf.org/html/rfc792
+    fn checksum(buf: &[u8]) -> u16 {
         let mut sum = 0u32;
-        for i in (0..buf.len()).step_by(2) {
-            let value = u16::from_be_bytes([buf[i], buf[i + 1]]);
-            sum += value as u32;
+        let mut chunks = buf.chunks_exact(2);
+        for chunk in &mut chunks {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+        if let [odd_byte] = *chunks.remainder() {
+            sum += u16::from_be_bytes([odd_byte, 0]) as u32;
         }
 
         let check = (sum >> 16) + (sum & 0xffff);
@@ -117,39 +182,20 @@ impl IcmpPacket {
 
 impl FromBuffer for IcmpPacket {
     fn from_buffer(buf: &[u8]) -> Result<IcmpPacket, ()> {
-        Ok(IcmpPacket::from_slice(&buf))
+        IcmpPacket::from_slice(&buf)
     }
 
     fn size(&self) -> usize {
-        match self {
-            IcmpPacket::EchoMessage(x) => 8 + x.data.len(),
-            _ => panic!(),
-        }
+        8 + self.payload.len()
     }
 }
 
 impl ToBuffer for IcmpPacket {
     fn to_buffer(&self, buf: &mut [u8]) {
-        match self {
-            IcmpPacket::EchoMessage(x) => {
-                buf[0..1].copy_from_slice(&[x.r#type.as_bytes()]);
-                buf[1..2].copy_from_slice(&[x.code]);
-                buf[2..4].copy_from_slice(&0u16.to_be_bytes());
-                buf[4..6].copy_from_slice(&x.identifier.to_be_bytes());
-                buf[6..8].copy_from_slice(&x.sequence_number.to_be_bytes());
-                buf[8..8 + x.data.len()].copy_from_slice(&x.data[..]);
-
-                let checksum = IcmpPacket::calculate_checksum(&buf[0..8 + x.data.len()]);
-                buf[2..4].copy_from_slice(&(checksum.to_be_bytes()));
-            }
-            _ => panic!(),
-        }
+        self.write(buf, self.checksum);
     }
 
     fn size(&self) -> usize {
-        match self {
-            IcmpPacket::EchoMessage(x) => 8 + x.data.len(),
-            _ => panic!(),
-        }
+        8 + self.payload.len()
     }
 }
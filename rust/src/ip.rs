@@ -18,13 +18,88 @@ impl Ipv4Addr {
     pub fn as_bytes(&self) -> [u8; 4] {
         self.0
     }
+
+    /// Is this the limited broadcast address, `255.255.255.255`? A directed
+    /// (subnet) broadcast address isn't recognized here, since that depends
+    /// on which network it's directed at - see `Ipv4Cidr::is_directed_broadcast`.
+    pub fn is_broadcast(&self) -> bool {
+        self.0 == [255, 255, 255, 255]
+    }
+
+    /// Is this a multicast address, i.e. in `224.0.0.0/4`?
+    pub fn is_multicast(&self) -> bool {
+        (224..=239).contains(&self.0[0])
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// An IPv4 address assigned to an interface, together with the prefix
+/// length (CIDR notation, e.g. `/24`) of the on-link network it belongs to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    address: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    /// `prefix_len` is clamped to `0..=32`.
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Ipv4Cidr {
+        Ipv4Cidr {
+            address,
+            prefix_len: core::cmp::min(prefix_len, 32),
+        }
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Build a CIDR from an address and a dotted-quad subnet mask, e.g.
+    /// `255.255.255.0` -> `/24`, as DHCP's subnet mask option (RFC2132
+    /// Section 3.3) carries it.
+    pub fn from_address_and_mask(address: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Cidr {
+        let prefix_len = u32::from_be_bytes(mask.as_bytes()).count_ones() as u8;
+        Ipv4Cidr::new(address, prefix_len)
+    }
+
+    /// Is `other` inside this CIDR's network, i.e. on-link for this address?
+    pub fn contains(&self, other: &Ipv4Addr) -> bool {
+        let mask = Ipv4Cidr::mask(self.prefix_len);
+        let network = u32::from_be_bytes(self.address.as_bytes()) & mask;
+        let candidate = u32::from_be_bytes(other.as_bytes()) & mask;
+        network == candidate
+    }
+
+    /// Is `other` this network's directed (subnet) broadcast address, i.e.
+    /// on-link per `contains` with every host bit set, e.g. `192.168.1.255`
+    /// for `192.168.1.0/24`?
+    pub fn is_directed_broadcast(&self, other: &Ipv4Addr) -> bool {
+        let mask = Ipv4Cidr::mask(self.prefix_len);
+        self.contains(other) && (u32::from_be_bytes(other.as_bytes()) | mask) == u32::MAX
+    }
+
+    /// The number of set high bits, as a subnet mask, e.g. 24 -> `/24` ->
+    /// `0xFFFFFF00`. A `/0` mask is all zero bits, which `u32::MAX << 32`
+    /// can't express directly (shifting by the full bit width overflows,
+    /// which panics in a debug build), hence the special case.
+    fn mask(prefix_len: u8) -> u32 {
+        if prefix_len == 0 {
+            0
+        } else {
+            u32::MAX << (32 - prefix_len)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Protocol {
     ICMP = 0x01,
     TCP = 0x06,
     UDP = 0x11,
+    ICMPV6 = 0x3A,
     UNKNOWN = 0xFF,
 }
 
@@ -34,15 +109,19 @@ impl Protocol {
             0x01u8 => Protocol::ICMP,
             0x06u8 => Protocol::TCP,
             0x11u8 => Protocol::UDP,
+            0x3Au8 => Protocol::ICMPV6,
             _ => Protocol::UNKNOWN,
         }
     }
 
-    fn as_bytes(&self) -> u8 {
+    // Visible to other protocol modules that need to write this as an IP
+    // header's protocol byte.
+    pub(crate) fn as_bytes(&self) -> u8 {
         match self {
             Protocol::ICMP => 0x01u8,
             Protocol::TCP => 0x06u8,
             Protocol::UDP => 0x11u8,
+            Protocol::ICMPV6 => 0x3Au8,
             Protocol::UNKNOWN => 0xFFu8,
         }
     }
@@ -131,6 +210,32 @@ impl Ipv4Packet {
         self.protocol
     }
 
+    pub fn source(&self) -> Ipv4Addr {
+        self.source_address
+    }
+
+    pub fn destination(&self) -> Ipv4Addr {
+        self.destination_address
+    }
+
+    pub fn total_length(&self) -> u16 {
+        self.total_length
+    }
+
+    pub fn identification(&self) -> u16 {
+        self.identification
+    }
+
+    /// Is the "more fragments" flag set (more fragments follow this one)?
+    pub fn more_fragments(&self) -> bool {
+        self.mf
+    }
+
+    /// This fragment's offset into the complete datagram, in bytes.
+    pub fn fragment_offset(&self) -> u16 {
+        self.fragment_offset * 8
+    }
+
     /// Write the header to `buf` with the appropriate checksum.
     ///
     /// The header is written to a stack allocated buffer, the checksum calculated, then the header is written to `buf`.
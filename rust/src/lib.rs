@@ -13,14 +13,22 @@ mod spinlock;
 
 mod arp;
 mod cpu;
+mod dhcp;
 mod e1000;
 mod ethernet;
+mod fault;
 mod icmp;
 mod ip;
 mod mm;
 mod net;
 mod packet_buffer;
+mod packet_fifo;
+mod pcap;
 mod pci;
+mod reassembly;
+mod rtl8139;
+mod tcp;
+mod trace;
 mod udp;
 
 #[panic_handler]
@@ -7,15 +7,19 @@ use core::slice;
 use crate::arp;
 use crate::arp::{ArpCache, ArpPacket};
 use crate::cpu::{rdtsc, CPU_FREQ_MHZ};
+use crate::dhcp;
+use crate::dhcp::{DhcpClient, DhcpEvent, DhcpPacket};
 use crate::e1000::E1000;
 use crate::ethernet::{EthernetAddress, EthernetFrame, Ethertype};
-use crate::icmp::IcmpPacket;
-use crate::icmp::{IcmpEchoMessage, Type};
-use crate::ip::{Ipv4Addr, Ipv4Packet, Protocol};
+use crate::icmp::{IcmpPacket, CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED, CODE_PORT_UNREACHABLE};
+use crate::ip::{Ipv4Addr, Ipv4Cidr, Ipv4Packet, Protocol};
 use crate::kernel::{argint, argptr, cprint};
-use crate::packet_buffer::{PacketBuffer, BUFFER_SIZE};
+use crate::packet_buffer::{PacketBuffer, ToBuffer, BUFFER_SIZE};
+use crate::reassembly::ReassemblyTable;
+use crate::rtl8139::Rtl8139;
 use crate::spinlock::Spinlock;
-use crate::udp::UdpPacket;
+use crate::tcp::{TcpConnection, TcpPacket, TcpState};
+use crate::udp::{ChecksumCapabilities, PseudoHeader, UdpPacket};
 
 /// The system network device.
 ///
@@ -28,9 +32,41 @@ static NETWORK_DEVICE: Spinlock<Option<Box<dyn NetworkDevice>>> = Spinlock::new(
 /// ARP Cache.
 static ARP_CACHE: Spinlock<ArpCache> = Spinlock::new(ArpCache::new());
 
+/// Drives the DHCPv4 handshake that leases this host's address, and tracks
+/// the resulting lease. `None` until `rustnetinit` starts the handshake.
+static DHCP_CLIENT: Spinlock<Option<DhcpClient>> = Spinlock::new(None);
+
+/// Every address assigned to this interface, each with the prefix length of
+/// its on-link network. DHCP assigns exactly one, but the list (rather than
+/// a single field) leaves room for further addresses (secondary/VIP
+/// addresses) to be layered on without another addressing model change.
+static INTERFACE_ADDRESSES: Spinlock<Vec<Ipv4Cidr>> = Spinlock::new(Vec::new());
+
+/// The default gateway, used as the next hop for any destination outside
+/// every prefix in `INTERFACE_ADDRESSES`.
+static GATEWAY: Spinlock<Option<Ipv4Addr>> = Spinlock::new(None);
+
 /// Active system sockets.
 static SOCKETS: Spinlock<BTreeMap<usize, Socket>> = Spinlock::new(BTreeMap::new());
 
+/// Next socket ID to hand out, shared by `create_socket` and `handle_tcp`'s
+/// accept path. A monotonic counter rather than `sockets.len()`, since
+/// `shutdown_socket` removes entries - once any socket has been closed, the
+/// map's length no longer tracks a high-water mark and re-derives an ID that
+/// can collide with one still in use.
+static NEXT_SOCKET_ID: Spinlock<u32> = Spinlock::new(0);
+
+/// Allocate a fresh, never-before-used socket ID.
+fn next_socket_id() -> u32 {
+    let mut next_socket_id = NEXT_SOCKET_ID.lock();
+    let id = *next_socket_id;
+    *next_socket_id += 1;
+    id
+}
+
+/// In-progress IPv4 fragment reassembly.
+static REASSEMBLY_TABLE: Spinlock<ReassemblyTable> = Spinlock::new(ReassemblyTable::new());
+
 /// Represents a device that can send and receive packets.
 pub trait NetworkDevice: Send + Sync {
     /// The hardware (MAC) address of the device.
@@ -45,19 +81,63 @@ pub trait NetworkDevice: Send + Sync {
     /// Clear interrupts.
     fn clear_interrupts(&mut self);
 
+    /// Borrow `len` bytes of a free transmit descriptor's buffer to
+    /// serialize a frame directly into, rather than building it up in a
+    /// heap allocation and copying it into device memory afterwards - the
+    /// same zero-copy borrow `recv` already hands back for a received
+    /// frame, just in the other direction. The frame is handed to the
+    /// device for transmission once the returned buffer is dropped.
+    ///
+    /// Returns `None` if no transmit descriptor is free right now rather
+    /// than blocking; callers are expected to fall back to `send` or retry.
+    fn transmit(&mut self, len: usize) -> Option<PacketBuffer>;
+
     /// Serialize a new packet.
-    fn send(&mut self, buf: PacketBuffer);
+    ///
+    /// Returns `Err(())` if the device cannot accept the frame (e.g. its
+    /// software transmit queue is full, or the frame is too large for a
+    /// transmit slot) rather than blocking; callers are expected to retry.
+    fn send(&mut self, buf: PacketBuffer) -> Result<(), ()>;
 
     /// Receive a new packet.
     fn recv(&mut self) -> Option<PacketBuffer>;
 }
 
-#[derive(Debug)]
+/// Probe the PCI bus for a supported network device, preferring the e1000
+/// family, and box up whichever one is found so the rest of the kernel
+/// stays device-agnostic.
+unsafe fn probe_network_device() -> Option<Box<dyn NetworkDevice>> {
+    if let Some(device) = E1000::new() {
+        cprint(b"Configured E1000 family device\n\x00".as_ptr());
+        return Some(Box::new(device));
+    }
+    if let Some(device) = Rtl8139::new() {
+        cprint(b"Configured RTL8139 family device\n\x00".as_ptr());
+        return Some(Box::new(device));
+    }
+    None
+}
+
+#[derive(Debug, PartialEq, Eq)]
 enum SocketType {
-    _TCP,
+    TCP,
     UDP,
 }
 
+/// How far a socket's in-progress `connect` has gotten, for the
+/// deadline-driven resolution path `poll` advances in place of a dedicated
+/// busy-wait.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectState {
+    /// No connect in progress.
+    Idle,
+    /// Waiting on ARP to resolve the next hop's hardware address.
+    ResolvingArp,
+    /// TCP only: the address resolved and our SYN is out, waiting for the
+    /// handshake to reach `Established`.
+    HandshakePending,
+}
+
 /// Represents one end of a socket connection.
 #[derive(Debug)]
 struct Socket {
@@ -68,26 +148,53 @@ struct Socket {
     dest_protocol_address: Option<Ipv4Addr>,
     dest_hardware_address: Option<EthernetAddress>,
     buffer: Vec<u8>,
+
+    /// This socket's TCP connection state machine. `None` for UDP sockets,
+    /// and for TCP sockets that haven't been `listen`-ed or `connect`-ed
+    /// yet.
+    tcp: Option<TcpConnection>,
+
+    /// Socket ids of connections accepted on a listening socket, waiting to
+    /// be handed back by `sys_accept`. Only ever populated on a socket in
+    /// `TcpState::Listen`.
+    accept_queue: Vec<u32>,
+
+    /// Stage of this socket's pending `connect`, if any.
+    connect_state: ConnectState,
+
+    /// Absolute `rdtsc` reading at which `poll` should next retry whatever
+    /// `connect_state` is waiting on. `None` while `connect_state` is
+    /// `Idle`.
+    connect_deadline: Option<u64>,
 }
 
 /// Initialize the network stack.
 ///
 /// Called on system start-up to initialize the kernel network stack. Routine
-/// searches for a compatible E1000 family network device and registers that
-/// device in the `NETWORK_DEVICE` global.
+/// searches for a compatible network device (see `probe_network_device`) and
+/// registers that device in the `NETWORK_DEVICE` global.
 #[no_mangle]
 unsafe extern "C" fn rustnetinit() {
-    // Setup the network device and panic if no device is avaliable.
-    let e1000_device = match E1000::new() {
+    // Probe for a supported network device and panic if none is avaliable.
+    let device = match probe_network_device() {
         Some(x) => x,
         None => panic!("no network device\n\x00"),
     };
-    cprint("Configured E1000 family device\n\x00".as_ptr());
 
-    // Assign a hardcoded, static IP to the device for now.
+    // Wrap the device in the debugging/testing middleware devices, so every
+    // frame it sends/receives is transparently pretty-printed
+    // (`trace::TracingDevice`) and captured (`pcap::PcapDevice`), without the
+    // driver itself, or any other call site, needing to know about either.
+    // Both are no-ops until toggled on at runtime. `fault::FaultInjectingDevice`
+    // stacks the same way when a test needs an unreliable link, but isn't
+    // wrapped in here by default.
+    let device = crate::trace::TracingDevice::new(device);
+    let device = crate::pcap::PcapDevice::new(device);
+    let mut device: Box<dyn NetworkDevice> = Box::new(device);
+
+    // No address until DHCP leases one.
+    device.set_protocol_address(Ipv4Addr::new(0, 0, 0, 0));
     let mut network_device = NETWORK_DEVICE.lock();
-    let mut device = Box::new(e1000_device);
-    device.set_protocol_address(Ipv4Addr::from(0x0A000002 as u32));
     *network_device = Some(device);
 
     // Setup other buffers and caches.
@@ -96,6 +203,20 @@ unsafe extern "C" fn rustnetinit() {
 
     let mut arp_cache = ARP_CACHE.lock();
     *arp_cache = ArpCache::new();
+    drop(sockets);
+    drop(arp_cache);
+
+    // Kick off the DHCPv4 handshake to lease a real address; `handle_dhcp`
+    // (reached via `handle_udp`) carries it the rest of the way to
+    // `DhcpEvent::Bound`, and `poll` renews it as the lease approaches T1.
+    let device: &mut Box<dyn NetworkDevice> = match *network_device {
+        Some(ref mut x) => x,
+        None => return,
+    };
+    let mut dhcp_client = DhcpClient::new(device.hardware_address());
+    let discover = dhcp_client.discover(rdtsc() as u32);
+    send_dhcp_broadcast(&discover, device);
+    *DHCP_CLIENT.lock() = Some(dhcp_client);
 }
 
 /// Entrypoint for network device interrupts.
@@ -116,6 +237,7 @@ unsafe extern "C" fn sys_socket() -> i32 {
 
     let domain = match domain {
         0 => SocketType::UDP,
+        1 => SocketType::TCP,
         _ => return -1,
     };
 
@@ -162,19 +284,40 @@ unsafe extern "C" fn sys_connect() -> i32 {
         return 1;
     }
 
+    // Non-blocking: `connect` registers/advances the pending ARP resolution
+    // or TCP handshake and returns immediately rather than spinning for it,
+    // the same would-block convention `sys_accept` uses for an empty accept
+    // queue. Callers are expected to retry.
     match connect(socket_id as u32, dest_address as u32, dest_port as u32) {
         Ok(()) => 0,
-        Err(()) => 1,
+        Err(ConnectError::WouldBlock) => -1,
+        Err(ConnectError::Failed) => 1,
     }
 }
 
 /// The listen system call.
 #[no_mangle]
-unsafe extern "C" fn sys_listen() {}
+unsafe extern "C" fn sys_listen() -> i32 {
+    let mut socket_id: i32 = 0;
+    argint(0, &mut socket_id);
+
+    match listen(socket_id as u32) {
+        Ok(()) => 0,
+        Err(()) => 1,
+    }
+}
 
 /// The accept system call.
 #[no_mangle]
-unsafe extern "C" fn sys_accept() {}
+unsafe extern "C" fn sys_accept() -> i32 {
+    let mut socket_id: i32 = 0;
+    argint(0, &mut socket_id);
+
+    match accept(socket_id as u32) {
+        Ok(new_socket_id) => new_socket_id as i32,
+        Err(()) => -1,
+    }
+}
 
 /// The send system call.
 #[no_mangle]
@@ -241,8 +384,8 @@ unsafe extern "C" fn sys_shutdown() -> i32 {
 
 /// Create a new socket of the specified domain and return the socket identifer.
 fn create_socket(domain: SocketType) -> u32 {
+    let socket_id = next_socket_id() as usize;
     let mut sockets = SOCKETS.lock();
-    let socket_id = sockets.len();
     let mut buffer = vec::Vec::<u8>::new();
     buffer.reserve(BUFFER_SIZE);
     sockets.insert(
@@ -255,15 +398,50 @@ fn create_socket(domain: SocketType) -> u32 {
             dest_protocol_address: None,
             dest_hardware_address: None,
             buffer: buffer,
+            tcp: None,
+            accept_queue: Vec::new(),
+            connect_state: ConnectState::Idle,
+            connect_deadline: None,
         },
     );
     socket_id as u32
 }
 
+/// Mark a bound TCP socket as listening for incoming connections.
+fn listen(socket_id: u32) -> Result<(), ()> {
+    let mut sockets = SOCKETS.lock();
+    let socket = match sockets.get_mut(&(socket_id as usize)) {
+        Some(x) => x,
+        None => return Err(()),
+    };
+
+    if socket.r#type != SocketType::TCP {
+        return Err(());
+    }
+    let source_port = socket.source_port.ok_or(())?;
+
+    socket.tcp = Some(TcpConnection::listen(source_port));
+    socket.accept_queue = Vec::new();
+
+    Ok(())
+}
+
+/// Non-blocking: hand back the socket id of a connection that has completed
+/// its handshake on a listening socket, if one is waiting.
+fn accept(socket_id: u32) -> Result<u32, ()> {
+    let mut sockets = SOCKETS.lock();
+    let socket = match sockets.get_mut(&(socket_id as usize)) {
+        Some(x) => x,
+        None => return Err(()),
+    };
+
+    if socket.accept_queue.is_empty() {
+        return Err(());
+    }
+    Ok(socket.accept_queue.remove(0))
+}
+
 /// Bind a socket to a local address and port.
-///
-/// TODO:
-/// 	- Don't hardcode address to 10.0.0.2
 fn bind(socket_id: u32, _source_address: u32, source_port: u16) -> Result<(), ()> {
     let mut sockets = SOCKETS.lock();
     let mut socket = match sockets.get_mut(&(socket_id as usize)) {
@@ -272,67 +450,344 @@ fn bind(socket_id: u32, _source_address: u32, source_port: u16) -> Result<(), ()
     };
 
     socket.source_port = Some(source_port);
-    socket.source_address = Some(Ipv4Addr::from(0x0A000002 as u32));
+    socket.source_address = Some(local_address());
 
     Ok(())
 }
 
+/// Errors `connect` can report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectError {
+    /// Still waiting on ARP resolution or a TCP handshake - `connect_state`
+    /// has been registered (or advanced) for `poll` to drive forward;
+    /// retry the call later.
+    WouldBlock,
+    /// The socket or network device doesn't exist.
+    Failed,
+}
+
+/// How long `poll` waits before retrying a pending ARP resolution or
+/// retransmitting an unacknowledged SYN.
+const CONNECT_RETRY_SECS: u64 = 1;
+
 /// Connect to a remote socket.
-fn connect(socket_id: u32, dest_address: u32, dest_port: u32) -> Result<(), ()> {
+///
+/// Non-blocking: rather than spinning for ARP resolution and (for TCP) the
+/// handshake, this registers the socket's `connect_state` and returns
+/// `Err(WouldBlock)` immediately, leaving `poll` to retry resolution and
+/// retransmit the SYN as their deadlines come due. A socket already waiting
+/// just reports how far it's gotten; call `connect` again once there's more
+/// progress to check for (e.g. after a `netpoll`/`netintr`).
+fn connect(socket_id: u32, dest_address: u32, dest_port: u32) -> Result<(), ConnectError> {
     let mut sockets = SOCKETS.lock();
-    let mut socket = match sockets.get_mut(&(socket_id as usize)) {
+    let socket = match sockets.get_mut(&(socket_id as usize)) {
         Some(x) => x,
-        None => return Err(()),
+        None => return Err(ConnectError::Failed),
     };
 
-    // Look up the desination hardware address from the cache or try and resolve it.
+    // The handshake is already under way: just report how far it's gotten.
+    if socket.connect_state == ConnectState::HandshakePending {
+        return match &socket.tcp {
+            Some(tcp) if tcp.state() == TcpState::Established => {
+                socket.connect_state = ConnectState::Idle;
+                socket.connect_deadline = None;
+                Ok(())
+            }
+            _ => Err(ConnectError::WouldBlock),
+        };
+    }
+
     let dest_protocol_address = Ipv4Addr::from(dest_address as u32);
+    if socket.connect_state == ConnectState::Idle {
+        // A fresh connect: stash the destination now, ahead of knowing its
+        // hardware address, so `poll` has what it needs to retry resolution
+        // and finish this once it lands.
+        socket.source_port = Some((1024 + socket_id) as u16);
+        socket.source_address = Some(source_address_for(dest_protocol_address));
+        socket.dest_port = Some((dest_port as i16).try_into().unwrap());
+        socket.dest_protocol_address = Some(dest_protocol_address);
+    }
+
+    // The next hop is who we actually ARP-resolve and address the Ethernet
+    // frame to - `dest_protocol_address` itself if it's on-link, otherwise
+    // the default gateway. `dest_protocol_address` remains the IP header's
+    // destination either way.
+    let next_hop_address = match next_hop(dest_protocol_address) {
+        Some(x) => x,
+        None => return Err(ConnectError::Failed),
+    };
+
     let dest_hardware_address = {
-        let arp_cache = ARP_CACHE.lock();
-        arp_cache.hardware_address(&dest_protocol_address)
+        let mut arp_cache = ARP_CACHE.lock();
+        arp_cache.hardware_address(&next_hop_address)
     };
     let dest_hardware_address = match dest_hardware_address {
         Some(x) => x,
         None => {
-            // Address not in the cache. Make the request, release the device lock try and
-            // and block until the address is resolved.
-            {
+            if socket.connect_state != ConnectState::ResolvingArp {
                 let mut device = NETWORK_DEVICE.lock();
                 let device: &mut Box<dyn NetworkDevice> = match *device {
                     Some(ref mut x) => x,
-                    None => return Err(()),
+                    None => return Err(ConnectError::Failed),
                 };
-                ArpCache::resolve(&dest_protocol_address, device);
-                drop(device);
+                let mut arp_cache = ARP_CACHE.lock();
+                arp_cache.resolve(&next_hop_address, device);
+
+                socket.connect_state = ConnectState::ResolvingArp;
+                socket.connect_deadline =
+                    Some(rdtsc() + CONNECT_RETRY_SECS * CPU_FREQ_MHZ * 1_000_000);
             }
+            return Err(ConnectError::WouldBlock);
+        }
+    };
+
+    let mut device = NETWORK_DEVICE.lock();
+    let device: &mut Box<dyn NetworkDevice> = match *device {
+        Some(ref mut x) => x,
+        None => return Err(ConnectError::Failed),
+    };
+    resolve_destination(socket, dest_protocol_address, dest_hardware_address, device);
 
-            // Wait 1 seconds for a response.
-            let timeout = rdtsc() + (CPU_FREQ_MHZ * 1_000_000);
-            loop {
-                if rdtsc() > timeout {
-                    break;
+    if socket.connect_state == ConnectState::HandshakePending {
+        return Err(ConnectError::WouldBlock);
+    }
+    Ok(())
+}
+
+/// Finish resolving a socket's destination now that its hardware address is
+/// known: populate the socket and, for TCP, send the initial SYN and move
+/// to `ConnectState::HandshakePending`. Used both by `connect`, when the
+/// address was already cached, and by `poll`, once a pending resolution
+/// completes.
+fn resolve_destination(
+    socket: &mut Socket,
+    dest_protocol_address: Ipv4Addr,
+    dest_hardware_address: EthernetAddress,
+    device: &mut Box<dyn NetworkDevice>,
+) {
+    socket.dest_hardware_address = Some(dest_hardware_address);
+    socket.connect_state = ConnectState::Idle;
+    socket.connect_deadline = None;
+
+    if socket.r#type != SocketType::TCP {
+        return;
+    }
+
+    // Active open: send our SYN now and wait for the SYN-ACK and final ACK
+    // to drive the handshake to completion as they arrive through
+    // `handle_tcp`.
+    let mut connection =
+        TcpConnection::connect(socket.source_port.unwrap(), socket.dest_port.unwrap());
+    let syn = connection.syn(PseudoHeader::Ipv4 {
+        source: socket.source_address.unwrap(),
+        destination: dest_protocol_address,
+    });
+    socket.tcp = Some(connection);
+    socket.connect_state = ConnectState::HandshakePending;
+    socket.connect_deadline = Some(rdtsc() + CONNECT_RETRY_SECS * CPU_FREQ_MHZ * 1_000_000);
+
+    let mut packet = PacketBuffer::new(BUFFER_SIZE);
+    packet.serialize(&syn);
+    send_ip_packet(
+        packet,
+        Protocol::TCP,
+        dest_protocol_address,
+        dest_hardware_address,
+        device,
+    );
+}
+
+/// Renew the DHCP lease once it reaches its T1 deadline.
+///
+/// Returns the deadline itself if it hasn't arrived yet, so `poll` can fold
+/// it into the earliest deadline it reports back to the caller; `None` if
+/// there's no lease to renew (no lease yet, or a renewal is already in
+/// flight).
+fn poll_dhcp(now: u64) -> Option<u64> {
+    let mut dhcp_client = DHCP_CLIENT.lock();
+    let client = dhcp_client.as_mut()?;
+
+    let deadline = client.renewal_deadline()?;
+    if now < deadline {
+        return Some(deadline);
+    }
+
+    let mut device = NETWORK_DEVICE.lock();
+    let device: &mut Box<dyn NetworkDevice> = match *device {
+        Some(ref mut x) => x,
+        None => return None,
+    };
+    let request = client.renew(now as u32);
+    send_dhcp_broadcast(&request, device);
+    None
+}
+
+/// Advance every socket's pending ARP resolution, TCP handshake, or
+/// unacknowledged data by whatever is due, in place of `connect`/`send`
+/// spinning for it.
+///
+/// For a socket in `ConnectState::ResolvingArp`, retries the ARP request
+/// (subject to `ArpCache`'s own one-per-second rate limit) and, once the
+/// address resolves, hands the socket to `resolve_destination`. For
+/// `ConnectState::HandshakePending`, retransmits the SYN if the handshake
+/// hasn't reached `Established` by its deadline. Independently of
+/// `connect_state`, any TCP socket with a due `TcpConnection::retransmit_deadline`
+/// (armed by `send_data` while data sent through it remains unacknowledged)
+/// has its oldest outstanding segment resent.
+///
+/// Returns the earliest upcoming deadline across all sockets - the `rdtsc`
+/// reading at which `poll` next has something to do - so the caller can
+/// sleep until then, or until the next `netintr`, instead of polling
+/// continuously. `None` means nothing is currently pending.
+fn poll() -> Option<u64> {
+    let now = rdtsc();
+
+    let dhcp_deadline = poll_dhcp(now);
+
+    let mut sockets = SOCKETS.lock();
+
+    let pending: Vec<usize> = sockets
+        .iter()
+        .filter(|(_, s)| s.connect_state != ConnectState::Idle)
+        .map(|(id, _)| *id)
+        .collect();
+    let retransmitting: Vec<usize> = sockets
+        .iter()
+        .filter(|(_, s)| matches!(&s.tcp, Some(tcp) if tcp.retransmit_deadline().is_some()))
+        .map(|(id, _)| *id)
+        .collect();
+    if pending.is_empty() && retransmitting.is_empty() {
+        return dhcp_deadline;
+    }
+
+    let mut device = NETWORK_DEVICE.lock();
+    let device: &mut Box<dyn NetworkDevice> = match *device {
+        Some(ref mut x) => x,
+        None => return dhcp_deadline,
+    };
+
+    let mut earliest: Option<u64> = dhcp_deadline;
+    for id in retransmitting {
+        let socket = sockets.get_mut(&id).unwrap();
+        let tcp = match &socket.tcp {
+            Some(x) => x,
+            None => continue,
+        };
+        let deadline = match tcp.retransmit_deadline() {
+            Some(x) => x,
+            None => continue,
+        };
+        if now < deadline {
+            earliest = Some(earliest.map_or(deadline, |e| u64::min(e, deadline)));
+            continue;
+        }
+
+        let pseudo_header = PseudoHeader::Ipv4 {
+            source: socket.source_address.unwrap(),
+            destination: socket.dest_protocol_address.unwrap(),
+        };
+        let dest_protocol_address = socket.dest_protocol_address.unwrap();
+        let dest_hardware_address = socket.dest_hardware_address.unwrap();
+        let segment = socket.tcp.as_mut().unwrap().retransmit(pseudo_header);
+        if let Some(segment) = segment {
+            let mut packet = PacketBuffer::new(BUFFER_SIZE);
+            packet.serialize(&segment);
+            send_ip_packet(
+                packet,
+                Protocol::TCP,
+                dest_protocol_address,
+                dest_hardware_address,
+                device,
+            );
+        }
+
+        if let Some(deadline) = socket.tcp.as_ref().unwrap().retransmit_deadline() {
+            earliest = Some(earliest.map_or(deadline, |e| u64::min(e, deadline)));
+        }
+    }
+
+    for id in pending {
+        let socket = sockets.get_mut(&id).unwrap();
+        let deadline = match socket.connect_deadline {
+            Some(x) => x,
+            None => continue,
+        };
+        if now < deadline {
+            earliest = Some(earliest.map_or(deadline, |e| u64::min(e, deadline)));
+            continue;
+        }
+
+        match socket.connect_state {
+            ConnectState::Idle => (),
+            ConnectState::ResolvingArp => {
+                let dest_protocol_address = match socket.dest_protocol_address {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let next_hop_address = match next_hop(dest_protocol_address) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let dest_hardware_address = {
+                    let mut arp_cache = ARP_CACHE.lock();
+                    arp_cache.hardware_address(&next_hop_address)
+                };
+                match dest_hardware_address {
+                    Some(hw) => resolve_destination(socket, dest_protocol_address, hw, device),
+                    None => {
+                        let mut arp_cache = ARP_CACHE.lock();
+                        arp_cache.resolve(&next_hop_address, device);
+                        socket.connect_deadline =
+                            Some(now + CONNECT_RETRY_SECS * CPU_FREQ_MHZ * 1_000_000);
+                    }
                 }
             }
+            ConnectState::HandshakePending => {
+                let established =
+                    matches!(&socket.tcp, Some(tcp) if tcp.state() == TcpState::Established);
+                if established {
+                    socket.connect_state = ConnectState::Idle;
+                    socket.connect_deadline = None;
+                    continue;
+                }
 
-            {
-                let arp_cache = ARP_CACHE.lock();
-                match arp_cache.hardware_address(&dest_protocol_address) {
-                    Some(x) => x,
-                    None => return Err(()),
+                if let Some(tcp) = &mut socket.tcp {
+                    let pseudo_header = PseudoHeader::Ipv4 {
+                        source: socket.source_address.unwrap(),
+                        destination: socket.dest_protocol_address.unwrap(),
+                    };
+                    let syn = tcp.syn(pseudo_header);
+                    let mut packet = PacketBuffer::new(BUFFER_SIZE);
+                    packet.serialize(&syn);
+                    send_ip_packet(
+                        packet,
+                        Protocol::TCP,
+                        socket.dest_protocol_address.unwrap(),
+                        socket.dest_hardware_address.unwrap(),
+                        device,
+                    );
                 }
+                socket.connect_deadline = Some(now + CONNECT_RETRY_SECS * CPU_FREQ_MHZ * 1_000_000);
             }
         }
-    };
 
-    // Populate the Socket with the address of the local adaptor, a new ephermal
-    // port and the details of the remote.
-    socket.source_port = Some((1024 + socket_id) as u16);
-    socket.source_address = Some(Ipv4Addr::from(0x0A000002 as u32));
-    socket.dest_port = Some((dest_port as i16).try_into().unwrap());
-    socket.dest_hardware_address = Some(dest_hardware_address);
-    socket.dest_protocol_address = Some(dest_protocol_address);
+        if let Some(deadline) = socket.connect_deadline {
+            earliest = Some(earliest.map_or(deadline, |e| u64::min(e, deadline)));
+        }
+    }
 
-    Ok(())
+    earliest
+}
+
+/// Kernel-internal entrypoint for the scheduler to advance pending ARP
+/// resolution, TCP handshakes and retransmissions (see `poll`), in place of
+/// a dedicated spin.
+///
+/// Returns the earliest `rdtsc` reading at which there's more work to do,
+/// or `0` if nothing is currently pending.
+#[no_mangle]
+unsafe extern "C" fn netpoll() -> u64 {
+    poll().unwrap_or(0)
 }
 
 /// Encapsulate and send data on a socket.
@@ -347,6 +802,37 @@ fn send(socket_id: u32, data: &[u8]) -> Result<u32, ()> {
         None => return Err(()),
     };
 
+    if socket.r#type == SocketType::TCP {
+        let pseudo_header = PseudoHeader::Ipv4 {
+            source: socket.source_address.unwrap(),
+            destination: socket.dest_protocol_address.unwrap(),
+        };
+        let segments = socket.tcp.as_mut().ok_or(())?.send_data(data, pseudo_header);
+        let dest_protocol_address = socket.dest_protocol_address.unwrap();
+        let dest_hardware_address = socket.dest_hardware_address.unwrap();
+        let sent: usize = segments.iter().map(|s| s.data().len()).sum();
+        drop(sockets);
+
+        let mut device = NETWORK_DEVICE.lock();
+        let device: &mut Box<dyn NetworkDevice> = match *device {
+            Some(ref mut x) => x,
+            None => return Err(()),
+        };
+        for segment in segments {
+            let mut packet = PacketBuffer::new(BUFFER_SIZE);
+            packet.serialize(&segment);
+            send_ip_packet(
+                packet,
+                Protocol::TCP,
+                dest_protocol_address,
+                dest_hardware_address,
+                device,
+            );
+        }
+
+        return Ok(sent as u32);
+    }
+
     // Create a new packet buffer.
     let mut packet = PacketBuffer::new(BUFFER_SIZE);
 
@@ -361,6 +847,11 @@ fn send(socket_id: u32, data: &[u8]) -> Result<u32, ()> {
         socket.source_port.unwrap(),
         socket.dest_port.unwrap(),
         data[..data_len as usize].to_vec(),
+        PseudoHeader::Ipv4 {
+            source: socket.source_address.unwrap(),
+            destination: socket.dest_protocol_address.unwrap(),
+        },
+        ChecksumCapabilities::default(),
     );
     packet.serialize(&udp_packet);
 
@@ -380,21 +871,28 @@ fn send(socket_id: u32, data: &[u8]) -> Result<u32, ()> {
     );
     packet.serialize(&ip_packet);
 
-    // Write the ethernet frame header and send the frame.
+    let dest_protocol_address = socket.dest_protocol_address.unwrap();
+
+    // Write a placeholder ethernet frame header - `ArpCache::send_or_queue`
+    // rewrites it with the real destination once that's resolved, whether
+    // that's immediately (the common case, since `connect` already resolved
+    // it) or after parking the packet if the cached entry has since expired.
     let mut device = NETWORK_DEVICE.lock();
     let device: &mut Box<dyn NetworkDevice> = match *device {
         Some(ref mut x) => x,
         None => return Err(()),
     };
 
-    let ethernet_frame = EthernetFrame::new(
-        socket.dest_hardware_address.unwrap(),
+    let placeholder_ethernet_frame = EthernetFrame::new(
+        EthernetAddress::from_slice(&[0, 0, 0, 0, 0, 0]),
         device.hardware_address(),
         Ethertype::IPV4,
     );
-    packet.serialize(&ethernet_frame);
+    packet.serialize(&placeholder_ethernet_frame);
 
-    device.send(packet);
+    let next_hop_address = next_hop(dest_protocol_address).unwrap_or(dest_protocol_address);
+    let mut arp_cache = ARP_CACHE.lock();
+    arp_cache.send_or_queue(packet, next_hop_address, device);
 
     // Encapsulate the data in a UDP packet.
     Ok(data_len as u32)
@@ -461,6 +959,214 @@ fn handle_interrupt() {
     }
 }
 
+/// Wrap a transport-layer payload (already serialized) in an IPv4 header
+/// addressed back to `destination` and an Ethernet frame addressed back to
+/// `ethernet_destination`, then send it out `device`.
+fn send_ip_packet(
+    mut payload: PacketBuffer,
+    protocol: Protocol,
+    destination: Ipv4Addr,
+    ethernet_destination: EthernetAddress,
+    device: &mut Box<dyn NetworkDevice>,
+) {
+    let ip_packet = Ipv4Packet::new(
+        0,
+        0,
+        (payload.len() + 20) as u16,
+        0,
+        true,
+        false,
+        0,
+        64,
+        protocol,
+        device.protocol_address(),
+        destination,
+    );
+    payload.serialize(&ip_packet);
+
+    let ethernet_frame = EthernetFrame::new(
+        ethernet_destination,
+        device.hardware_address(),
+        Ethertype::IPV4,
+    );
+    payload.serialize(&ethernet_frame);
+    let _ = device.send(payload);
+}
+
+/// Like `send_ip_packet`, but resolves the destination's hardware address
+/// through `next_hop`/`ArpCache` rather than requiring the caller to already
+/// know it - for packets sent on our own initiative rather than in reply to
+/// a frame that just arrived (e.g. a reassembly-timeout Time Exceeded),
+/// where there's no sender hardware address to reuse.
+fn send_ip_packet_routed(
+    mut payload: PacketBuffer,
+    protocol: Protocol,
+    destination: Ipv4Addr,
+    device: &mut Box<dyn NetworkDevice>,
+) {
+    let ip_packet = Ipv4Packet::new(
+        0,
+        0,
+        (payload.len() + 20) as u16,
+        0,
+        true,
+        false,
+        0,
+        64,
+        protocol,
+        device.protocol_address(),
+        destination,
+    );
+    payload.serialize(&ip_packet);
+
+    let placeholder_ethernet_frame = EthernetFrame::new(
+        EthernetAddress::from_slice(&[0, 0, 0, 0, 0, 0]),
+        device.hardware_address(),
+        Ethertype::IPV4,
+    );
+    payload.serialize(&placeholder_ethernet_frame);
+
+    let next_hop_address = next_hop(destination).unwrap_or(destination);
+    let mut arp_cache = ARP_CACHE.lock();
+    arp_cache.send_or_queue(payload, next_hop_address, device);
+}
+
+/// Broadcast a DHCP message: UDP from `dhcp::CLIENT_PORT` to
+/// `dhcp::SERVER_PORT`, IPv4 `0.0.0.0` to the limited broadcast address,
+/// inside a broadcast Ethernet frame. Used for every message the client
+/// sends - `send_ip_packet` can't, since it addresses its IPv4 header from
+/// `device.protocol_address()`, which isn't leased yet when negotiating (or
+/// renewing) the very address it would use.
+fn send_dhcp_broadcast(packet: &DhcpPacket, device: &mut Box<dyn NetworkDevice>) {
+    let mut dhcp_bytes = vec![0u8; ToBuffer::size(packet)];
+    packet.to_buffer(&mut dhcp_bytes);
+
+    let source = Ipv4Addr::new(0, 0, 0, 0);
+    let destination = Ipv4Addr::new(255, 255, 255, 255);
+
+    let udp_packet = UdpPacket::new(
+        dhcp::CLIENT_PORT,
+        dhcp::SERVER_PORT,
+        dhcp_bytes,
+        PseudoHeader::Ipv4 {
+            source,
+            destination,
+        },
+        ChecksumCapabilities::default(),
+    );
+
+    let mut buffer = PacketBuffer::new(BUFFER_SIZE);
+    buffer.serialize(&udp_packet);
+
+    let ip_packet = Ipv4Packet::new(
+        0,
+        0,
+        (buffer.len() + 20) as u16,
+        0,
+        true,
+        false,
+        0,
+        64,
+        Protocol::UDP,
+        source,
+        destination,
+    );
+    buffer.serialize(&ip_packet);
+
+    let broadcast_hardware_address =
+        EthernetAddress::from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    let ethernet_frame = EthernetFrame::new(
+        broadcast_hardware_address,
+        device.hardware_address(),
+        Ethertype::IPV4,
+    );
+    buffer.serialize(&ethernet_frame);
+
+    let _ = device.send(buffer);
+}
+
+/// Feed an inbound DHCP message to the client state machine: send on a
+/// DHCPREQUEST in reply to an offer, or install the leased address,
+/// subnet mask and router once the server ACKs it.
+fn handle_dhcp(data: &[u8], device: &mut Box<dyn NetworkDevice>) {
+    let mut buffer = PacketBuffer::new_from_bytes(data.as_ptr(), data.len());
+    let packet = match buffer.parse::<DhcpPacket>() {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+
+    let mut dhcp_client = DHCP_CLIENT.lock();
+    let client = match dhcp_client.as_mut() {
+        Some(x) => x,
+        None => return,
+    };
+
+    match client.handle(packet) {
+        DhcpEvent::Request(request) => send_dhcp_broadcast(&request, device),
+        DhcpEvent::Bound(config) => {
+            device.set_protocol_address(config.address);
+            let prefix_len = config
+                .subnet_mask
+                .map(|mask| Ipv4Cidr::from_address_and_mask(config.address, mask).prefix_len())
+                .unwrap_or(32);
+            *INTERFACE_ADDRESSES.lock() = vec![Ipv4Cidr::new(config.address, prefix_len)];
+            *GATEWAY.lock() = config.router;
+
+            // Announce the new binding so any peer that cached our address
+            // from a previous lease refreshes it immediately, rather than
+            // waiting on its own entry to time out.
+            ARP_CACHE.lock().announce(device);
+        }
+        DhcpEvent::None => (),
+    }
+}
+
+/// The address leased via DHCP, or the unspecified address before a lease
+/// has been negotiated.
+fn local_address() -> Ipv4Addr {
+    DHCP_CLIENT
+        .lock()
+        .as_ref()
+        .and_then(|client| client.config())
+        .map(|config| config.address)
+        .unwrap_or(Ipv4Addr::new(0, 0, 0, 0))
+}
+
+/// The address to ARP-resolve in order to reach `destination`: `destination`
+/// itself if it's on-link for one of `INTERFACE_ADDRESSES`, otherwise the
+/// default gateway. `None` means `destination` is off-link and no gateway is
+/// configured, so it can't be reached at all.
+///
+/// The IP header's destination address is unaffected by this - only the
+/// Ethernet frame's destination hardware address changes when routing via a
+/// gateway.
+fn next_hop(destination: Ipv4Addr) -> Option<Ipv4Addr> {
+    let on_link = INTERFACE_ADDRESSES
+        .lock()
+        .iter()
+        .any(|cidr| cidr.contains(&destination));
+    if on_link {
+        Some(destination)
+    } else {
+        *GATEWAY.lock()
+    }
+}
+
+/// The source address to use for a datagram to `destination`: the assigned
+/// address whose prefix most specifically contains `destination` (longest
+/// prefix match), falling back to the first assigned address, or the
+/// unspecified address if none has been assigned yet.
+fn source_address_for(destination: Ipv4Addr) -> Ipv4Addr {
+    let addresses = INTERFACE_ADDRESSES.lock();
+    addresses
+        .iter()
+        .filter(|cidr| cidr.contains(&destination))
+        .max_by_key(|cidr| cidr.prefix_len())
+        .or_else(|| addresses.first())
+        .map(|cidr| cidr.address())
+        .unwrap_or(Ipv4Addr::new(0, 0, 0, 0))
+}
+
 /// Main entrypoint into the kernel network stack.
 ///
 /// Handles a single, ethernet frame encapsulated packet. Potentially writes
@@ -473,47 +1179,128 @@ fn handle_packet(mut buffer: PacketBuffer, device: &mut Box<dyn NetworkDevice>)
 
     match ethernet_frame.ethertype {
         Ethertype::IPV4 => {
+            // The IP datagram (header and payload), kept around in case we
+            // need to quote it back to the sender in an ICMP error.
+            let original_datagram = buffer.remaining().to_vec();
+
             let ip_packet = match buffer.parse::<Ipv4Packet>() {
                 Ok(x) => x,
                 Err(_) => return,
             };
 
+            // Fragments carry a payload that doesn't reflect the complete
+            // datagram yet; feed them through the reassembly table and only
+            // proceed once (or if) it hands back the whole thing.
+            let is_fragment = ip_packet.more_fragments() || ip_packet.fragment_offset() != 0;
+
+            let mut reassembled;
+            let payload: &mut PacketBuffer = if is_fragment {
+                let payload_len = (ip_packet.total_length() as usize)
+                    .saturating_sub(20)
+                    .min(buffer.remaining().len());
+                let fragment_payload = &buffer.remaining()[..payload_len];
+
+                let header_len = 20.min(original_datagram.len());
+                let (data, expired) = REASSEMBLY_TABLE.lock().insert(
+                    ip_packet.source(),
+                    ip_packet.destination(),
+                    ip_packet.identification(),
+                    ip_packet.protocol(),
+                    ip_packet.fragment_offset(),
+                    ip_packet.more_fragments(),
+                    fragment_payload,
+                    &original_datagram[..header_len],
+                );
+
+                // Report any other datagram whose reassembly timed out
+                // while we were at it - there's no just-arrived frame from
+                // its sender to reuse a hardware address from, so route it
+                // like any other self-originated packet. Same as
+                // `handle_udp`'s Destination Unreachable path, a
+                // broadcast/multicast/directed-broadcast destination isn't
+                // owed an ICMP error (RFC1122 Section 3.2.2).
+                for (source_address, destination_address, quote) in expired {
+                    let is_directed_broadcast = INTERFACE_ADDRESSES
+                        .lock()
+                        .iter()
+                        .any(|cidr| cidr.is_directed_broadcast(&destination_address));
+                    if destination_address.is_broadcast()
+                        || destination_address.is_multicast()
+                        || is_directed_broadcast
+                    {
+                        continue;
+                    }
+
+                    let reply = IcmpPacket::time_exceeded(
+                        CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED,
+                        &quote,
+                    );
+                    let mut packet = PacketBuffer::new(BUFFER_SIZE);
+                    packet.serialize(&reply);
+                    send_ip_packet_routed(packet, Protocol::ICMP, source_address, device);
+                }
+
+                match data {
+                    Some(data) => {
+                        reassembled = PacketBuffer::new_from_bytes(data.as_ptr(), data.len());
+                        &mut reassembled
+                    }
+                    None => return,
+                }
+            } else {
+                &mut buffer
+            };
+
             match ip_packet.protocol() {
-                Protocol::ICMP => match handle_icmp(&mut buffer) {
-                    Some(mut x) => {
-                        let ip_packet = Ipv4Packet::new(
-                            0,
-                            0,
-                            (x.len() + 20) as u16,
-                            0,
-                            true,
-                            false,
-                            0,
-                            64,
+                Protocol::ICMP => {
+                    if let Some(reply) = handle_icmp(payload) {
+                        send_ip_packet(
+                            reply,
                             Protocol::ICMP,
-                            device.protocol_address(),
                             ip_packet.source(),
-                        );
-                        x.serialize(&ip_packet);
-
-                        let ethernet_frame = EthernetFrame::new(
                             ethernet_frame.source,
-                            device.hardware_address(),
-                            Ethertype::IPV4,
+                            device,
                         );
-                        x.serialize(&ethernet_frame);
-                        device.send(x);
                     }
-                    None => (),
-                },
+                }
                 Protocol::UDP => {
-                    handle_udp(&mut buffer);
+                    if let Some(reply) = handle_udp(
+                        payload,
+                        ip_packet.source(),
+                        ip_packet.destination(),
+                        &original_datagram,
+                        device,
+                    ) {
+                        send_ip_packet(
+                            reply,
+                            Protocol::ICMP,
+                            ip_packet.source(),
+                            ethernet_frame.source,
+                            device,
+                        );
+                    }
+                }
+                Protocol::TCP => {
+                    if let Some(reply) = handle_tcp(
+                        payload,
+                        ip_packet.source(),
+                        ip_packet.destination(),
+                        ethernet_frame.source,
+                    ) {
+                        send_ip_packet(
+                            reply,
+                            Protocol::TCP,
+                            ip_packet.source(),
+                            ethernet_frame.source,
+                            device,
+                        );
+                    }
                 }
-                Protocol::TCP => (),
+                Protocol::ICMPV6 => (),
                 Protocol::UNKNOWN => (),
             }
         }
-        Ethertype::ARP => match handle_arp(&mut buffer, &device) {
+        Ethertype::ARP => match handle_arp(&mut buffer, device) {
             Some(mut x) => {
                 // Encapsulate the ARP response.
                 let ethernet_frame = EthernetFrame::new(
@@ -522,7 +1309,7 @@ fn handle_packet(mut buffer: PacketBuffer, device: &mut Box<dyn NetworkDevice>)
                     Ethertype::ARP,
                 );
                 x.serialize(&ethernet_frame);
-                device.send(x);
+                let _ = device.send(x);
             }
             None => (),
         },
@@ -541,16 +1328,13 @@ pub fn handle_icmp(buffer: &mut PacketBuffer) -> Option<PacketBuffer> {
         Err(_) => return None,
     };
 
-    match icmp_packet {
-        IcmpPacket::EchoMessage(x) => {
-            if x.r#type == Type::EchoRequest {
-                let reply = IcmpPacket::EchoMessage(IcmpEchoMessage::from_request(x));
-                let mut packet = PacketBuffer::new(BUFFER_SIZE);
-                packet.serialize(&reply);
-                return Some(packet);
-            }
-        }
+    if icmp_packet.is_echo_request() {
+        let reply = IcmpPacket::echo_reply(&icmp_packet);
+        let mut packet = PacketBuffer::new(BUFFER_SIZE);
+        packet.serialize(&reply);
+        return Some(packet);
     }
+
     None
 }
 
@@ -560,44 +1344,88 @@ pub fn handle_icmp(buffer: &mut PacketBuffer) -> Option<PacketBuffer> {
 /// serialized to the network.
 pub fn handle_arp(
     buffer: &mut PacketBuffer,
-    device: &Box<dyn NetworkDevice>,
+    device: &mut Box<dyn NetworkDevice>,
 ) -> Option<PacketBuffer> {
     let arp_packet = match buffer.parse::<ArpPacket>() {
         Ok(x) => x,
         Err(_) => return None,
     };
 
-    // Get the protocol address of the device.
-    match arp_packet.oper {
-        arp::Operation::Request => {
-            // Is this a request for us?
-            if arp_packet.tpa == device.protocol_address() {
-                // Build the ARP reply.
-                let hardware_address = device.hardware_address();
-                let reply = ArpPacket::from_request(&arp_packet, hardware_address);
-                let mut packet = PacketBuffer::new(BUFFER_SIZE);
-                packet.serialize(&reply);
-                return Some(packet);
-            }
-        }
-        arp::Operation::Reply => {
-            let mut arp_cache = ARP_CACHE.lock();
-            arp_cache.reply(arp_packet);
+    // Build a reply first, if this is a request addressed to us - either
+    // our device-level address, or one of the (possibly several) addresses
+    // assigned via DHCP - while we still hold `arp_packet` by reference.
+    let packet = if let arp::Operation::Request = arp_packet.oper {
+        let for_us = arp_packet.tpa == device.protocol_address()
+            || INTERFACE_ADDRESSES
+                .lock()
+                .iter()
+                .any(|cidr| cidr.address() == arp_packet.tpa);
+        if for_us {
+            let hardware_address = device.hardware_address();
+            let reply = ArpPacket::from_request(&arp_packet, hardware_address);
+            let mut packet = PacketBuffer::new(BUFFER_SIZE);
+            packet.serialize(&reply);
+            Some(packet)
+        } else {
+            None
         }
-        arp::Operation::Unknown => (),
-    }
-    None
+    } else {
+        None
+    };
+
+    // Opportunistically learn the sender's mapping from *any* incoming
+    // request or reply, not just one addressed to us - standard
+    // gratuitous-ARP behavior, and how we notice a peer's address changed
+    // even when it wasn't asking us anything.
+    ARP_CACHE.lock().reply(arp_packet, device);
+
+    packet
 }
 
 /// Handle a UDP packet.
 ///
 /// If this packet is destined for a socket and that socket has space in its
 /// buffer, copy the packet data into the socket buffer.
-pub fn handle_udp(buffer: &mut PacketBuffer) {
+///
+/// `original_datagram` is the IP datagram (header and payload) `buffer` was
+/// parsed from, used to quote back to the sender if we need to report that
+/// no socket is listening on the destination port.
+///
+/// No Destination Unreachable is generated for a datagram sent to a
+/// broadcast or multicast address - per RFC1122 Section 3.2.2, an error
+/// would otherwise fan out replies to every host on the segment instead of
+/// just the one sender.
+pub fn handle_udp(
+    buffer: &mut PacketBuffer,
+    source_address: Ipv4Addr,
+    destination_address: Ipv4Addr,
+    original_datagram: &[u8],
+    device: &mut Box<dyn NetworkDevice>,
+) -> Option<PacketBuffer> {
     let packet = match buffer.parse::<UdpPacket>() {
         Ok(x) => x,
-        Err(_) => return,
+        Err(_) => return None,
+    };
+
+    let pseudo_header = PseudoHeader::Ipv4 {
+        source: source_address,
+        destination: destination_address,
     };
+    let capabilities = ChecksumCapabilities::default();
+    if packet
+        .verify_checksum(pseudo_header, capabilities)
+        .is_err()
+    {
+        return None;
+    }
+
+    // DHCP replies land on the client port; route them to the lease state
+    // machine instead of the active-socket lookup below, since no socket is
+    // ever bound to it.
+    if packet.dest_port() == dhcp::CLIENT_PORT {
+        handle_dhcp(packet.data(), device);
+        return None;
+    }
 
     // Is this packet destined for an active socket?
     let mut sockets = SOCKETS.lock();
@@ -612,7 +1440,24 @@ pub fn handle_udp(buffer: &mut PacketBuffer) {
 
         match socket_id {
             Some(id) => *id,
-            None => return,
+            None => {
+                drop(sockets);
+                let is_directed_broadcast = INTERFACE_ADDRESSES
+                    .lock()
+                    .iter()
+                    .any(|cidr| cidr.is_directed_broadcast(&destination_address));
+                if destination_address.is_broadcast()
+                    || destination_address.is_multicast()
+                    || is_directed_broadcast
+                {
+                    return None;
+                }
+                let reply =
+                    IcmpPacket::destination_unreachable(CODE_PORT_UNREACHABLE, original_datagram);
+                let mut packet = PacketBuffer::new(BUFFER_SIZE);
+                packet.serialize(&reply);
+                return Some(packet);
+            }
         }
     };
 
@@ -623,7 +1468,135 @@ pub fn handle_udp(buffer: &mut PacketBuffer) {
 
     // Do we have space in the socket buffer for the new data?
     if socket.buffer.len() + packet.data().len() >= BUFFER_SIZE {
-        return;
+        return None;
     }
     socket.buffer.extend_from_slice(&packet.data());
+    None
+}
+
+/// Handle a TCP segment.
+///
+/// Demultiplexes to either an existing connection's socket (matched on local
+/// port, remote port and remote address, since several connections can share
+/// one local port; the local address isn't compared) or, for
+/// a SYN with no existing connection, a socket in `TcpState::Listen` on the
+/// destination port - a brand new per-connection socket is created for it,
+/// reusing `TcpConnection::listen`/`handle` exactly as chunk1-6 wrote them.
+/// In-order data is copied into `socket.buffer`, just as `handle_udp` does.
+///
+/// `source_hardware_address` is the sender's Ethernet address, stashed on a
+/// newly-accepted connection's socket so replies don't need a fresh ARP
+/// lookup for an address we just received a frame from.
+pub fn handle_tcp(
+    buffer: &mut PacketBuffer,
+    source_address: Ipv4Addr,
+    destination_address: Ipv4Addr,
+    source_hardware_address: EthernetAddress,
+) -> Option<PacketBuffer> {
+    let segment = match buffer.parse::<TcpPacket>() {
+        Ok(x) => x,
+        Err(_) => return None,
+    };
+
+    let pseudo_header = PseudoHeader::Ipv4 {
+        source: source_address,
+        destination: destination_address,
+    };
+    let capabilities = ChecksumCapabilities::default();
+    if segment
+        .verify_checksum(pseudo_header, capabilities)
+        .is_err()
+    {
+        return None;
+    }
+
+    let mut sockets = SOCKETS.lock();
+
+    let connection_id = sockets.iter().find_map(|(id, s)| {
+        if s.tcp.is_some()
+            && s.source_port == Some(segment.dest_port())
+            && s.dest_port == Some(segment.source_port())
+            && s.dest_protocol_address == Some(source_address)
+        {
+            Some(*id)
+        } else {
+            None
+        }
+    });
+
+    if let Some(id) = connection_id {
+        let (reply, completed_handshake) = {
+            let socket = sockets.get_mut(&id).unwrap();
+            let tcp = socket.tcp.as_mut().unwrap();
+            let was_syn_received = tcp.state() == TcpState::SynReceived;
+            let in_order = segment.seq_number() == tcp.receive_next();
+
+            let reply = tcp.handle(&segment, pseudo_header);
+            // A duplicate or out-of-order segment's data isn't accepted by
+            // `handle` (it doesn't advance `receive_next`), so it shouldn't
+            // be appended to the application-visible stream either.
+            if in_order && !segment.data().is_empty() {
+                socket.buffer.extend_from_slice(segment.data());
+            }
+
+            let now_established = socket.tcp.as_ref().unwrap().state() == TcpState::Established;
+            (reply, was_syn_received && now_established)
+        };
+
+        // The handshake just completed: hand this connection's socket id to
+        // the listening socket on the same local port, so `sys_accept` can
+        // return it.
+        if completed_handshake {
+            let source_port = sockets.get(&id).unwrap().source_port;
+            if let Some((_, listener)) = sockets.iter_mut().find(|(_, s)| {
+                matches!(&s.tcp, Some(t) if t.state() == TcpState::Listen) && s.source_port == source_port
+            }) {
+                listener.accept_queue.push(id as u32);
+            }
+        }
+
+        return reply.map(|r| {
+            let mut packet = PacketBuffer::new(BUFFER_SIZE);
+            packet.serialize(&r);
+            packet
+        });
+    }
+
+    // No existing connection: is this a SYN for a socket in LISTEN?
+    if !segment.flags().syn {
+        return None;
+    }
+
+    let is_listening = sockets.iter().any(|(_, s)| {
+        matches!(&s.tcp, Some(t) if t.state() == TcpState::Listen)
+            && s.source_port == Some(segment.dest_port())
+    });
+    if !is_listening {
+        return None;
+    }
+
+    let mut connection = TcpConnection::listen(segment.dest_port());
+    let reply = connection.handle(&segment, pseudo_header)?;
+
+    let new_socket_id = next_socket_id();
+    sockets.insert(
+        new_socket_id as usize,
+        Socket {
+            r#type: SocketType::TCP,
+            source_port: Some(segment.dest_port()),
+            source_address: Some(destination_address),
+            dest_port: Some(segment.source_port()),
+            dest_protocol_address: Some(source_address),
+            dest_hardware_address: Some(source_hardware_address),
+            buffer: Vec::new(),
+            tcp: Some(connection),
+            accept_queue: Vec::new(),
+            connect_state: ConnectState::Idle,
+            connect_deadline: None,
+        },
+    );
+
+    let mut packet = PacketBuffer::new(BUFFER_SIZE);
+    packet.serialize(&reply);
+    Some(packet)
 }
@@ -1,51 +1,121 @@
+use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
 
 pub static BUFFER_SIZE: usize = 2048;
 
+/// Where a `PacketBuffer`'s bytes actually live.
+enum Storage {
+    /// A heap buffer owned outright by the `PacketBuffer`, used when we build
+    /// up a packet via repeated `serialize` calls.
+    Owned(Vec<u8>),
+    /// Bytes borrowed directly from a device's DMA buffer. Dropping the
+    /// `PacketBuffer` runs `reclaim` to hand the backing memory back to the
+    /// device (e.g. rearming a receive descriptor), so no copy is needed to
+    /// get the frame out of the ring.
+    Loaned {
+        ptr: *mut u8,
+        reclaim: Option<Box<dyn FnMut() + Send>>,
+    },
+}
+
 /// Represents raw packet data.
-///
-/// TODO: Stack allocated buffer?
 pub struct PacketBuffer {
     /// The raw packet data.
-    buf: Vec<u8>,
+    buf: Storage,
     /// The size of the raw packet.
     size: usize,
     /// The number of bytes we have parsed so far into the buffer.
     offset: usize,
     /// Has the buffer been written to?
     written: bool,
+    /// Set by a device that offloaded IP/transport checksum verification to
+    /// hardware on receive, so upper layers can skip re-checking it. `false`
+    /// for anything built/received without hardware checksum support.
+    checksum_verified: bool,
 }
 
 impl PacketBuffer {
     /// Create a new buffer with the specified size.
     pub fn new(size: usize) -> PacketBuffer {
         PacketBuffer {
-            buf: vec![0u8; size],
+            buf: Storage::Owned(vec![0u8; size]),
             size: size,
             offset: 0,
             written: false,
+            checksum_verified: false,
         }
     }
 
-    /// Create a new buffer from the data provided.
+    /// Create a new buffer from the data provided, copying it onto the heap.
     pub fn new_from_bytes(data: *const u8, size: usize) -> PacketBuffer {
-        let mut packet_buffer = PacketBuffer {
-            buf: vec![0u8; size],
+        let mut buf = vec![0u8; size];
+        unsafe {
+            core::ptr::copy(data, buf.as_mut_ptr(), size);
+        }
+        PacketBuffer {
+            buf: Storage::Owned(buf),
             size: size,
             offset: 0,
             written: false,
-        };
-        unsafe {
-            core::ptr::copy(data, packet_buffer.buf.as_mut_ptr(), size);
+            checksum_verified: false,
+        }
+    }
+
+    /// Create a new buffer that borrows `size` bytes at `ptr` rather than
+    /// copying them, running `reclaim` when the buffer is dropped.
+    ///
+    /// `ptr` must remain valid for `size` bytes until `reclaim` runs, and
+    /// nothing else may touch that memory while this buffer is alive.
+    pub unsafe fn new_loaned(
+        ptr: *mut u8,
+        size: usize,
+        reclaim: impl FnMut() + Send + 'static,
+    ) -> PacketBuffer {
+        PacketBuffer {
+            buf: Storage::Loaned {
+                ptr,
+                reclaim: Some(Box::new(reclaim)),
+            },
+            size: size,
+            offset: 0,
+            written: false,
+            checksum_verified: false,
+        }
+    }
+
+    /// Record that a device already verified this buffer's IP/transport
+    /// checksums in hardware.
+    pub fn set_checksum_verified(&mut self, verified: bool) {
+        self.checksum_verified = verified;
+    }
+
+    /// Has a device already verified this buffer's checksums in hardware?
+    pub fn checksum_verified(&self) -> bool {
+        self.checksum_verified
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        match &self.buf {
+            Storage::Owned(v) => &v[..],
+            Storage::Loaned { ptr, .. } => unsafe { core::slice::from_raw_parts(*ptr, self.size) },
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match &mut self.buf {
+            Storage::Owned(v) => &mut v[..],
+            Storage::Loaned { ptr, .. } => unsafe {
+                core::slice::from_raw_parts_mut(*ptr, self.size)
+            },
         }
-        packet_buffer
     }
 
     /// Parse a new packet from the buffer.
     /// TODO: Zero-copy?
     pub fn parse<T: FromBuffer>(&mut self) -> Result<T, ()> {
-        let value = match T::from_buffer(&self.buf[self.offset..]) {
+        let offset = self.offset;
+        let value = match T::from_buffer(&self.as_slice()[offset..]) {
             Ok(x) => x,
             Err(_) => return Err(()),
         };
@@ -58,9 +128,20 @@ impl PacketBuffer {
     pub fn serialize<T: ToBuffer>(&mut self, value: &T) {
         self.offset += value.size();
         self.written = true;
-        let start = self.buf.len() - self.offset;
+        let start = self.size - self.offset;
         let end = start + value.size();
-        value.to_buffer(&mut self.buf[start..end]);
+        value.to_buffer(&mut self.as_mut_slice()[start..end]);
+    }
+
+    /// Overwrite the frontmost (i.e. most recently serialized) header
+    /// of an already-serialized buffer with `value` - e.g. rewriting a
+    /// queued packet's Ethernet header once the ARP resolution it was
+    /// waiting on completes. `value` must be the same size as the header
+    /// being replaced.
+    pub fn rewrite_front<T: ToBuffer>(&mut self, value: &T) {
+        let start = self.size - self.offset;
+        let end = start + value.size();
+        value.to_buffer(&mut self.as_mut_slice()[start..end]);
     }
 
     /// Return the size of the buffer.
@@ -68,12 +149,36 @@ impl PacketBuffer {
         self.offset
     }
 
+    /// Return the unparsed bytes from the current cursor to the end of the
+    /// buffer, without advancing it - for payloads handled as raw bytes
+    /// rather than through `FromBuffer` (e.g. collecting IP fragment
+    /// payloads ahead of reassembly).
+    pub fn remaining(&self) -> &[u8] {
+        &self.as_slice()[self.offset..]
+    }
+
     /// Return a pointer to the underlying buffer.
     pub fn as_ptr(&self) -> *const u8 {
         if self.written {
-            self.buf[self.buf.len() - self.offset..].as_ptr()
+            self.as_slice()[self.size - self.offset..].as_ptr()
         } else {
-            self.buf[..self.offset].as_ptr()
+            self.as_slice()[..self.offset].as_ptr()
+        }
+    }
+}
+
+// A loaned buffer's raw pointer is exclusively owned by whichever
+// `PacketBuffer` currently holds it (ownership moves with the value, same as
+// the `Vec` case), so it's safe to move across threads/interrupt contexts,
+// e.g. into a `Spinlock`-protected `PacketFifo`.
+unsafe impl Send for PacketBuffer {}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        if let Storage::Loaned { reclaim, .. } = &mut self.buf {
+            if let Some(reclaim) = reclaim {
+                reclaim();
+            }
         }
     }
 }
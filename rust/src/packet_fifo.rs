@@ -0,0 +1,71 @@
+use alloc::collections::VecDeque;
+
+use crate::packet_buffer::PacketBuffer;
+
+/// A bounded queue of `PacketBuffer`s, capped by both entry count and total
+/// byte budget, mirroring the gem5 `pktfifo` device model.
+///
+/// Used to decouple an interrupt handler (which pushes frames in as fast as
+/// the ring drains) from a kernel thread (which pops them at its own pace)
+/// without either side blocking the other.
+pub struct PacketFifo {
+    /// Each entry's frame size alongside its buffer, since `PacketBuffer::len`
+    /// (the parse offset) isn't a reliable stand-in for it - see `push`.
+    queue: VecDeque<(PacketBuffer, usize)>,
+    max_packets: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl PacketFifo {
+    pub const fn new(max_packets: usize, max_bytes: usize) -> PacketFifo {
+        PacketFifo {
+            queue: VecDeque::new(),
+            max_packets,
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    /// Is there room for one more packet of `size` bytes?
+    pub fn has_space(&self, size: usize) -> bool {
+        self.queue.len() < self.max_packets && self.bytes + size <= self.max_bytes
+    }
+
+    /// Enqueue `buf`, handing it back if the FIFO has no room for it.
+    ///
+    /// `size` is the caller's own idea of `buf`'s real frame size, rather
+    /// than `buf.len()` - for a freshly-loaned, unparsed RX buffer, `len()`
+    /// (the parse offset) reads 0, which would make the byte budget this
+    /// FIFO exists to enforce vacuously always satisfied.
+    pub fn push(&mut self, buf: PacketBuffer, size: usize) -> Result<(), PacketBuffer> {
+        if !self.has_space(size) {
+            return Err(buf);
+        }
+        self.bytes += size;
+        self.queue.push_back((buf, size));
+        Ok(())
+    }
+
+    /// Dequeue the oldest packet, if any.
+    pub fn pop(&mut self) -> Option<PacketBuffer> {
+        let (buf, size) = self.queue.pop_front()?;
+        self.bytes -= size;
+        Some(buf)
+    }
+
+    /// Borrow the oldest packet without dequeuing it.
+    pub fn peek(&self) -> Option<&PacketBuffer> {
+        self.queue.front().map(|(buf, _)| buf)
+    }
+
+    /// The number of packets currently queued.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// The total size, in bytes, of the packets currently queued.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
@@ -1,11 +1,37 @@
+use alloc::vec::Vec;
+
 use crate::asm::{in_dw, out_dw};
 
 /// PCI I/O.
 const PCI_CONFIG_ADDR: u16 = 0xCF8;
 const PCI_CONFIG_DATA: u16 = 0xCFC;
 
+/// Vendor id read back for a slot with nothing attached.
+const VENDOR_ID_ABSENT: u16 = 0xFFFF;
+
+/// Header type bit indicating a device implements more than one function.
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+
+/// A base address register, decoded into the address space it maps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Bar {
+    /// An I/O space BAR, with its port base address.
+    Io { port: u32 },
+    /// A 32-bit memory space BAR.
+    Memory32 { base: u32, prefetchable: bool },
+    /// A 64-bit memory space BAR, built from two consecutive registers.
+    Memory64 { base: u64, prefetchable: bool },
+    /// No BAR is present at this index, or it is the upper half of a
+    /// preceding 64-bit BAR.
+    None,
+}
+
 /// Represents a PCI configuration space header.
+#[derive(Debug, Clone)]
 pub struct PciConfig {
+    bus: u8,
+    device: u8,
+    function: u8,
     base_addr: u32,
     vendor_id: u16,
     device_id: u16,
@@ -21,30 +47,82 @@ pub struct PciConfig {
 }
 
 impl PciConfig {
-    /// Read a new PciConfig struct from a memory mapped I/O address.
-    pub fn new(base_addr: u32) -> Result<PciConfig, ()> {
+    /// Read a complete PciConfig header for a given bus/device/function.
+    ///
+    /// Returns `Err(())` if no device is present at this slot.
+    fn new(bus: u8, device: u8, function: u8) -> Result<PciConfig, ()> {
+        let base_addr = config_address(bus, device, function);
+
         unsafe {
             let vendor_id = Self::read_vendor_id(base_addr);
+            if vendor_id == VENDOR_ID_ABSENT {
+                return Err(());
+            }
             let device_id = Self::read_device_id(base_addr);
-            let bar_0 = Self::read_bar(base_addr, 0);
+
+            // Offset 0x04: command (low 16) / status (high 16).
+            let word_04 = read_u32(base_addr, 0x04);
+            let command = (word_04 & 0xFFFF) as u16;
+            let status = (word_04 >> 16) as u16;
+
+            // Offset 0x08: revision id (byte 0) / class code (bytes 1..4).
+            let word_08 = read_u32(base_addr, 0x08);
+            let revision_id = (word_08 & 0xFF) as u8;
+            let class_code = [
+                ((word_08 >> 8) & 0xFF) as u8,  // Programming interface.
+                ((word_08 >> 16) & 0xFF) as u8, // Subclass.
+                ((word_08 >> 24) & 0xFF) as u8, // Base class.
+            ];
+
+            // Offset 0x0C: cache line size / latency timer / header type / BIST.
+            let word_0c = read_u32(base_addr, 0x0C);
+            let cache_line_size = (word_0c & 0xFF) as u8;
+            let lat_timer = ((word_0c >> 8) & 0xFF) as u8;
+            let header_type = ((word_0c >> 16) & 0xFF) as u8;
+            let bist = ((word_0c >> 24) & 0xFF) as u8;
+
+            let mut bar = [0u32; 6];
+            for (i, slot) in bar.iter_mut().enumerate() {
+                *slot = read_u32(base_addr, 0x10 + (i as u32) * 4);
+            }
 
             Ok(PciConfig {
-                base_addr: base_addr,
-                vendor_id: vendor_id,
-                device_id: device_id,
-                command: 0,
-                status: 0,
-                revision_id: 0,
-                class_code: [0u8; 3],
-                cache_line_size: 0,
-                lat_timer: 0,
-                header_type: 0,
-                bist: 0,
-                bar: [bar_0, 0, 0, 0, 0, 0],
+                bus,
+                device,
+                function,
+                base_addr,
+                vendor_id,
+                device_id,
+                command,
+                status,
+                revision_id,
+                class_code,
+                cache_line_size,
+                lat_timer,
+                header_type,
+                bist,
+                bar,
             })
         }
     }
 
+    /// Is this device capable of more than one function?
+    fn is_multifunction(&self) -> bool {
+        self.header_type & HEADER_TYPE_MULTIFUNCTION != 0
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+
+    pub fn function(&self) -> u8 {
+        self.function
+    }
+
     /// Return the vendor id associated with the device.
     pub fn vendor_id(&self) -> u16 {
         self.vendor_id
@@ -55,62 +133,158 @@ impl PciConfig {
         self.device_id
     }
 
-    /// Return the value of the ith base address register.
+    pub fn command(&self) -> u16 {
+        self.command
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn revision_id(&self) -> u8 {
+        self.revision_id
+    }
+
+    /// Three byte class code: `[programming_interface, subclass, base_class]`.
+    pub fn class_code(&self) -> [u8; 3] {
+        self.class_code
+    }
+
+    pub fn header_type(&self) -> u8 {
+        self.header_type
+    }
+
+    /// Return the raw value of the ith base address register.
     pub fn bar(&self, i: u8) -> u32 {
         self.bar[i as usize]
     }
 
-    /// Set the device as a bus master.
-    pub unsafe fn set_bus_master(&self) {
-        let mut command: u32 = 0x0;
-        let mut j = 1;
-        for i in (4..=5).rev() {
-            out_dw(PCI_CONFIG_ADDR, self.base_addr | i);
-            let data = in_dw(PCI_CONFIG_DATA);
-            command |= data << (j * 8);
-            j -= 1;
+    /// Decode the ith BAR into the address space it maps.
+    ///
+    /// A 64-bit memory BAR spans two consecutive registers; querying the
+    /// upper half directly returns `Bar::None`.
+    pub fn decode_bar(&self, i: u8) -> Bar {
+        // Walk the BARs below `i`, stepping over 64-bit pairs, to tell
+        // whether `i` is the upper half of one.
+        let is_memory64 = |raw: u32| raw & 0x1 == 0 && (raw >> 1) & 0x3 == 0x2;
+        let mut j = 0;
+        while j < i as usize {
+            if is_memory64(self.bar[j]) {
+                if j + 1 == i as usize {
+                    return Bar::None;
+                }
+                j += 2;
+            } else {
+                j += 1;
+            }
+        }
+
+        let raw = self.bar[i as usize];
+        if raw == 0 {
+            return Bar::None;
+        }
+
+        if raw & 0x1 != 0 {
+            return Bar::Io {
+                port: raw & !0x3,
+            };
+        }
+
+        let prefetchable = raw & (1 << 3) != 0;
+        match (raw >> 1) & 0x3 {
+            0x0 => Bar::Memory32 {
+                base: raw & !0xF,
+                prefetchable,
+            },
+            0x2 => {
+                let low = (raw & !0xF) as u64;
+                let high = *self.bar.get(i as usize + 1).unwrap_or(&0) as u64;
+                Bar::Memory64 {
+                    base: (high << 32) | low,
+                    prefetchable,
+                }
+            }
+            _ => Bar::None,
         }
+    }
 
-        // Set the bus master flag and write back the command register.
+    /// Set the device as a bus master.
+    pub unsafe fn set_bus_master(&self) {
+        let mut command = read_u32(self.base_addr, 0x04);
         command |= 1 << 2;
-        out_dw(PCI_CONFIG_ADDR, self.base_addr | 4);
-        out_dw(PCI_CONFIG_DATA, command);
+        write_u32(self.base_addr, 0x04, command);
     }
 
     /// Read a PCI vendor identifier.
     unsafe fn read_vendor_id(base_addr: u32) -> u16 {
-        let mut result: u16 = 0x0;
-        for i in (0..=1).rev() {
-            out_dw(PCI_CONFIG_ADDR, base_addr | i);
-            let data = in_dw(PCI_CONFIG_DATA);
-            result |= (data as u16) << (i * 8);
-        }
-        result
+        (read_u32(base_addr, 0x00) & 0xFFFF) as u16
     }
 
     /// Read a PCI device identifier.
     unsafe fn read_device_id(base_addr: u32) -> u16 {
-        let mut result: u16 = 0x0;
-        let mut j = 1;
-        for i in (2..=3).rev() {
-            out_dw(PCI_CONFIG_ADDR, base_addr | i);
-            let data = in_dw(PCI_CONFIG_DATA);
-            result |= (data as u16) << (j * 8);
-            j -= 1;
-        }
-        result
-    }
-
-    /// Read the nth BAR register.
-    unsafe fn read_bar(base_addr: u32, _n: u32) -> u32 {
-        let mut result: u32 = 0x0;
-        let mut j = 3;
-        for i in (16..=19).rev() {
-            out_dw(PCI_CONFIG_ADDR, base_addr | i);
-            let data = in_dw(PCI_CONFIG_DATA);
-            result |= data << (j * 8);
-            j -= 1;
+        (read_u32(base_addr, 0x00) >> 16) as u16
+    }
+}
+
+/// Build the `CONFIG_ADDRESS` value selecting a bus/device/function's
+/// configuration space, with the register offset left at zero.
+fn config_address(bus: u8, device: u8, function: u8) -> u32 {
+    0x80000000 | ((bus as u32) << 16) | ((device as u32) << 11) | ((function as u32) << 8)
+}
+
+/// Read a 32-bit register at `offset` (must be 4-byte aligned) from the
+/// configuration space selected by `base_addr`.
+unsafe fn read_u32(base_addr: u32, offset: u32) -> u32 {
+    out_dw(PCI_CONFIG_ADDR, base_addr | offset);
+    in_dw(PCI_CONFIG_DATA)
+}
+
+/// Write a 32-bit register at `offset` (must be 4-byte aligned) in the
+/// configuration space selected by `base_addr`.
+unsafe fn write_u32(base_addr: u32, offset: u32, data: u32) {
+    out_dw(PCI_CONFIG_ADDR, base_addr | offset);
+    out_dw(PCI_CONFIG_DATA, data);
+}
+
+/// Enumerate every PCI device present on the bus.
+///
+/// Walks buses `0..=255`, devices `0..=31`, and functions `0..=7`, skipping
+/// functions above zero unless the device's header type advertises
+/// multifunction support.
+pub fn probe() -> Vec<PciConfig> {
+    let mut devices = Vec::new();
+
+    for bus in 0..=255u16 {
+        for device in 0..=31u8 {
+            match PciConfig::new(bus as u8, device, 0) {
+                Ok(config) => {
+                    let multifunction = config.is_multifunction();
+                    devices.push(config);
+
+                    if multifunction {
+                        for function in 1..=7u8 {
+                            if let Ok(config) = PciConfig::new(bus as u8, device, function) {
+                                devices.push(config);
+                            }
+                        }
+                    }
+                }
+                Err(()) => continue,
+            }
         }
-        result
     }
+
+    devices
+}
+
+/// Find the first device matching a (vendor, device) identifier pair.
+pub fn find_by_id(devices: &[PciConfig], vendor_id: u16, device_id: u16) -> Option<&PciConfig> {
+    devices
+        .iter()
+        .find(|d| d.vendor_id() == vendor_id && d.device_id() == device_id)
+}
+
+/// Find the first device matching a three-byte class code.
+pub fn find_by_class(devices: &[PciConfig], class_code: [u8; 3]) -> Option<&PciConfig> {
+    devices.iter().find(|d| d.class_code() == class_code)
 }
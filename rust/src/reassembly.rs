@@ -0,0 +1,215 @@
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::cpu::{rdtsc, CPU_FREQ_MHZ};
+use crate::ip::{Ipv4Addr, Protocol};
+use crate::mm::PAGE_SIZE;
+
+/// How long an incomplete datagram sits in the table before we give up on
+/// it and reclaim its buffer, per RFC791's suggested 15-30s reassembly
+/// timeout.
+const REASSEMBLY_TIMEOUT_SECS: u64 = 30;
+
+/// How many datagrams can be reassembling concurrently. Each entry's data
+/// buffer is a single `PAGE_SIZE` allocation, so this also bounds the total
+/// bytes the table can hold.
+const MAX_ENTRIES: usize = 16;
+
+/// "Infinity": no fragment has told us where the datagram ends yet.
+const INFINITY: u32 = u32::MAX;
+
+/// Identifies which datagram a fragment belongs to, per RFC791.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ReassemblyKey {
+    source_address: Ipv4Addr,
+    destination_address: Ipv4Addr,
+    identification: u16,
+    protocol: Protocol,
+}
+
+/// A still-missing byte range: `[first, last)`, i.e. `last` is exclusive.
+#[derive(Debug, Clone, Copy)]
+struct Hole {
+    first: u32,
+    last: u32,
+}
+
+/// One datagram's reassembly-in-progress state.
+struct ReassemblyEntry {
+    /// Data collected so far. Allocated once at `PAGE_SIZE` - the largest
+    /// single allocation `KernelAllocator` permits - and never resized;
+    /// fragments that would overflow it are dropped.
+    data: Vec<u8>,
+    /// Byte ranges not yet filled in by any fragment, per RFC815's "holes"
+    /// algorithm. Reassembly is complete once this is empty.
+    holes: Vec<Hole>,
+    /// The datagram's total length, known once a fragment with MF clear
+    /// arrives.
+    total_length: Option<u32>,
+    /// `rdtsc()` reading when this entry was created, for timeout eviction.
+    created: u64,
+    /// The IP header (no options) of whichever fragment created this entry,
+    /// kept to quote back in a Time Exceeded message if reassembly times
+    /// out. All fragments of a datagram share the same header fields bar
+    /// flags/offset/total length, so any one of them is representative
+    /// enough for this purpose.
+    header: Vec<u8>,
+}
+
+impl ReassemblyEntry {
+    fn new(now: u64, header: &[u8]) -> Self {
+        ReassemblyEntry {
+            data: vec![0u8; PAGE_SIZE],
+            holes: vec![Hole {
+                first: 0,
+                last: INFINITY,
+            }],
+            total_length: None,
+            created: now,
+            header: header.to_vec(),
+        }
+    }
+
+    /// The bytes a Time Exceeded message quotes back to the sender: this
+    /// datagram's IP header plus the first 8 bytes of payload collected so
+    /// far (zero if the fragment covering that range hasn't arrived yet).
+    fn quote(&self) -> Vec<u8> {
+        let mut quote = self.header.clone();
+        quote.extend_from_slice(&self.data[..8.min(self.data.len())]);
+        quote
+    }
+
+    /// Insert one fragment's payload at byte offset `offset`, splitting any
+    /// hole it overlaps. Returns the complete datagram once every hole has
+    /// been filled.
+    fn insert(&mut self, offset: u32, payload: &[u8], more_fragments: bool) -> Option<Vec<u8>> {
+        let first = offset;
+        let last = offset + payload.len() as u32;
+
+        if last as usize > self.data.len() {
+            // Would overflow our page-sized buffer; drop this fragment.
+            return None;
+        }
+        self.data[first as usize..last as usize].copy_from_slice(payload);
+
+        if !more_fragments {
+            self.total_length = Some(last);
+        }
+
+        let mut remaining = Vec::with_capacity(self.holes.len() + 1);
+        for hole in self.holes.drain(..) {
+            if last <= hole.first || first >= hole.last {
+                // This fragment doesn't overlap the hole at all.
+                remaining.push(hole);
+                continue;
+            }
+            if first > hole.first {
+                remaining.push(Hole {
+                    first: hole.first,
+                    last: first,
+                });
+            }
+            if last < hole.last && more_fragments {
+                remaining.push(Hole {
+                    first: last,
+                    last: hole.last,
+                });
+            }
+        }
+        self.holes = remaining;
+
+        match (self.holes.is_empty(), self.total_length) {
+            (true, Some(total)) => Some(self.data[..total as usize].to_vec()),
+            _ => None,
+        }
+    }
+}
+
+/// Reassembles fragmented IPv4 datagrams, keyed on
+/// `(source_address, destination_address, identification, protocol)` as
+/// RFC791 requires.
+pub struct ReassemblyTable {
+    entries: BTreeMap<ReassemblyKey, ReassemblyEntry>,
+}
+
+impl ReassemblyTable {
+    pub const fn new() -> Self {
+        ReassemblyTable {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Drop entries that have sat incomplete for longer than the
+    /// reassembly timeout, returning the original sender, original
+    /// destination and Time Exceeded quote for each one so the caller can
+    /// report the failure back to it - the destination is handed back too
+    /// since whether that's appropriate (e.g. a broadcast/multicast
+    /// destination isn't owed an ICMP error) is the caller's call, same as
+    /// `handle_udp`'s Destination Unreachable path.
+    fn evict_expired(&mut self, now: u64) -> Vec<(Ipv4Addr, Ipv4Addr, Vec<u8>)> {
+        let timeout_ticks = REASSEMBLY_TIMEOUT_SECS * CPU_FREQ_MHZ * 1_000_000;
+        let expired_keys: Vec<ReassemblyKey> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now.saturating_sub(entry.created) >= timeout_ticks)
+            .map(|(key, _)| *key)
+            .collect();
+
+        expired_keys
+            .into_iter()
+            .map(|key| {
+                let entry = self.entries.remove(&key).unwrap();
+                (key.source_address, key.destination_address, entry.quote())
+            })
+            .collect()
+    }
+
+    /// Feed one fragment into the table, returning the fully reassembled
+    /// datagram once every fragment for it has arrived, and the sender,
+    /// destination and quote of any other datagram whose reassembly timed
+    /// out in the process.
+    ///
+    /// `fragment_offset` is in bytes (i.e. already multiplied up from the
+    /// wire's 8-byte units). `header` is this fragment's IP header (no
+    /// options), kept only in case this is the fragment that creates the
+    /// entry.
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert(
+        &mut self,
+        source_address: Ipv4Addr,
+        destination_address: Ipv4Addr,
+        identification: u16,
+        protocol: Protocol,
+        fragment_offset: u16,
+        more_fragments: bool,
+        payload: &[u8],
+        header: &[u8],
+    ) -> (Option<Vec<u8>>, Vec<(Ipv4Addr, Ipv4Addr, Vec<u8>)>) {
+        let now = rdtsc();
+        let expired = self.evict_expired(now);
+
+        let key = ReassemblyKey {
+            source_address,
+            destination_address,
+            identification,
+            protocol,
+        };
+
+        if !self.entries.contains_key(&key) {
+            if self.entries.len() >= MAX_ENTRIES {
+                // Table full; drop this fragment rather than exceed our
+                // allocation cap.
+                return (None, expired);
+            }
+            self.entries.insert(key, ReassemblyEntry::new(now, header));
+        }
+
+        let entry = self.entries.get_mut(&key).unwrap();
+        let complete = entry.insert(fragment_offset as u32, payload, more_fragments);
+        if complete.is_some() {
+            self.entries.remove(&key);
+        }
+        (complete, expired)
+    }
+}
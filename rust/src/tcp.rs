@@ -0,0 +1,885 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::cpu::{rdtsc, CPU_FREQ_MHZ};
+use crate::packet_buffer::{FromBuffer, ToBuffer};
+use crate::udp::{ChecksumCapabilities, PseudoHeader};
+
+/// How long `retransmit` waits, once armed by `send_data`, before resending
+/// the oldest unacknowledged segment - the same fixed-RTO approximation
+/// `net::CONNECT_RETRY_SECS` uses for the handshake's SYN retries, rather
+/// than an RTT-sampled timer.
+const RETRANSMIT_TIMEOUT_SECS: u64 = 1;
+
+/// The protocol number TCP uses as the pseudo-header's "protocol" field.
+const PROTOCOL_TCP: u8 = 0x06;
+
+/// A TCP header has no options in this minimal implementation, so its data
+/// offset is always 5 32-bit words (20 bytes).
+const HEADER_LEN: usize = 20;
+
+/// A TCP sequence (or acknowledgment) number.
+///
+/// Wraps modulo 2^32 (RFC793 Section 3.3), so comparing or differencing raw
+/// `u32`s breaks as soon as a connection's sequence space wraps around.
+/// Every comparison and difference here instead goes through
+/// `wrapping_sub`, read as a signed offset into a window centered on the
+/// left-hand side - the same trick used for comparing wrapping jiffies
+/// counters. This is what keeps a shrinking remote window, or a SYN-ACK
+/// whose ack number hasn't caught up yet, from triggering a subtract-with-
+/// underflow panic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpSeqNumber(u32);
+
+impl TcpSeqNumber {
+    pub fn new(value: u32) -> TcpSeqNumber {
+        TcpSeqNumber(value)
+    }
+
+    /// Generate an initial send sequence number from the cycle counter, so
+    /// it is effectively random and (bar the 1-in-2^32 coincidence) nonzero,
+    /// rather than always starting connections at 0.
+    pub fn from_tsc() -> TcpSeqNumber {
+        TcpSeqNumber(rdtsc() as u32)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    /// `self + rhs`, wrapping at 2^32.
+    pub fn wrapping_add(&self, rhs: u32) -> TcpSeqNumber {
+        TcpSeqNumber(self.0.wrapping_add(rhs))
+    }
+
+    /// The signed distance from `other` to `self`, wrapping at 2^32:
+    /// positive when `self` is ahead of `other` in sequence-space order,
+    /// negative when behind.
+    pub fn wrapping_sub(&self, other: TcpSeqNumber) -> i32 {
+        self.0.wrapping_sub(other.0) as i32
+    }
+}
+
+impl PartialOrd for TcpSeqNumber {
+    fn partial_cmp(&self, other: &TcpSeqNumber) -> Option<core::cmp::Ordering> {
+        Some(self.wrapping_sub(*other).cmp(&0))
+    }
+}
+
+/// The control bits carried in a TCP header (RFC793 Section 3.1). The
+/// later ECN/NS bits (RFC3168) aren't needed by this minimal
+/// implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TcpFlags {
+    pub urg: bool,
+    pub ack: bool,
+    pub psh: bool,
+    pub rst: bool,
+    pub syn: bool,
+    pub fin: bool,
+}
+
+impl TcpFlags {
+    fn from_byte(byte: u8) -> TcpFlags {
+        TcpFlags {
+            urg: byte & 0x20 > 0,
+            ack: byte & 0x10 > 0,
+            psh: byte & 0x08 > 0,
+            rst: byte & 0x04 > 0,
+            syn: byte & 0x02 > 0,
+            fin: byte & 0x01 > 0,
+        }
+    }
+
+    fn as_byte(&self) -> u8 {
+        let mut byte = 0u8;
+        byte |= (self.urg as u8) << 5;
+        byte |= (self.ack as u8) << 4;
+        byte |= (self.psh as u8) << 3;
+        byte |= (self.rst as u8) << 2;
+        byte |= (self.syn as u8) << 1;
+        byte |= self.fin as u8;
+        byte
+    }
+}
+
+/// Errors surfaced while parsing or verifying a TCP segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpError {
+    /// The segment's checksum didn't match the pseudo-header, header and payload.
+    Checksum,
+}
+
+/// A TCP segment (header and payload), without options.
+///
+/// RFC793 Section 3.1
+/// https://tools.ietf.org/html/rfc793
+#[derive(Debug, Clone)]
+pub struct TcpPacket {
+    source_port: u16,
+    dest_port: u16,
+    seq_number: TcpSeqNumber,
+    ack_number: TcpSeqNumber,
+    flags: TcpFlags,
+    window_size: u16,
+    checksum: u16,
+    urgent_pointer: u16,
+    data: Vec<u8>,
+}
+
+impl TcpPacket {
+    /// Build a new TCP segment, computing a real checksum over
+    /// `pseudo_header` and the header/payload unless `capabilities` says to
+    /// skip it.
+    pub fn new(
+        source_port: u16,
+        dest_port: u16,
+        seq_number: TcpSeqNumber,
+        ack_number: TcpSeqNumber,
+        flags: TcpFlags,
+        window_size: u16,
+        data: Vec<u8>,
+        pseudo_header: PseudoHeader,
+        capabilities: ChecksumCapabilities,
+    ) -> TcpPacket {
+        let mut packet = TcpPacket {
+            source_port,
+            dest_port,
+            seq_number,
+            ack_number,
+            flags,
+            window_size,
+            checksum: 0,
+            urgent_pointer: 0,
+            data,
+        };
+        if capabilities.tcp.tx() {
+            packet.checksum = packet.compute_checksum(pseudo_header);
+        }
+        packet
+    }
+
+    /// Verify this segment's checksum against the pseudo-header that
+    /// carried it, unless `capabilities` says to skip verification.
+    pub fn verify_checksum(
+        &self,
+        pseudo_header: PseudoHeader,
+        capabilities: ChecksumCapabilities,
+    ) -> Result<(), TcpError> {
+        if !capabilities.tcp.rx() {
+            return Ok(());
+        }
+
+        if self.compute_checksum(pseudo_header) != self.checksum {
+            return Err(TcpError::Checksum);
+        }
+
+        Ok(())
+    }
+
+    /// Recompute what this segment's checksum should be, given the
+    /// pseudo-header it was (or will be) carried in.
+    ///
+    /// The checksum is the 16-bit one's-complement sum over the pseudo-
+    /// header followed by the TCP header (with the checksum field zeroed)
+    /// and the payload, padded with a zero byte if that's of odd length.
+    fn compute_checksum(&self, pseudo_header: PseudoHeader) -> u16 {
+        let mut bytes = vec![0u8; ToBuffer::size(self)];
+        self.to_buffer(&mut bytes);
+        bytes[16..18].copy_from_slice(&0u16.to_be_bytes());
+
+        let mut sum = pseudo_header.sum(bytes.len() as u32, PROTOCOL_TCP);
+
+        let mut chunks = bytes.chunks_exact(2);
+        for chunk in &mut chunks {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+        if let [last] = chunks.remainder() {
+            sum += (*last as u32) << 8;
+        }
+
+        let check = (sum >> 16) + (sum & 0xffff);
+        let check = (check >> 16) + (check & 0xffff);
+        !(check as u16)
+    }
+
+    fn from_slice(buf: &[u8]) -> Result<TcpPacket, ()> {
+        if buf.len() < HEADER_LEN {
+            return Err(());
+        }
+
+        let data_offset = ((buf[12] >> 4) as usize) * 4;
+        if data_offset < HEADER_LEN || buf.len() < data_offset {
+            return Err(());
+        }
+
+        Ok(TcpPacket {
+            source_port: u16::from_be_bytes([buf[0], buf[1]]),
+            dest_port: u16::from_be_bytes([buf[2], buf[3]]),
+            seq_number: TcpSeqNumber::new(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]])),
+            ack_number: TcpSeqNumber::new(u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]])),
+            flags: TcpFlags::from_byte(buf[13]),
+            window_size: u16::from_be_bytes([buf[14], buf[15]]),
+            checksum: u16::from_be_bytes([buf[16], buf[17]]),
+            urgent_pointer: u16::from_be_bytes([buf[18], buf[19]]),
+            data: buf[data_offset..].to_vec(),
+        })
+    }
+
+    pub fn source_port(&self) -> u16 {
+        self.source_port
+    }
+
+    pub fn dest_port(&self) -> u16 {
+        self.dest_port
+    }
+
+    pub fn seq_number(&self) -> TcpSeqNumber {
+        self.seq_number
+    }
+
+    pub fn ack_number(&self) -> TcpSeqNumber {
+        self.ack_number
+    }
+
+    pub fn flags(&self) -> TcpFlags {
+        self.flags
+    }
+
+    pub fn window(&self) -> u16 {
+        self.window_size
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
+    }
+}
+
+impl FromBuffer for TcpPacket {
+    fn from_buffer(buf: &[u8]) -> Result<TcpPacket, ()> {
+        TcpPacket::from_slice(&buf)
+    }
+
+    fn size(&self) -> usize {
+        HEADER_LEN + self.data.len()
+    }
+}
+
+impl ToBuffer for TcpPacket {
+    fn to_buffer(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.dest_port.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.seq_number.value().to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ack_number.value().to_be_bytes());
+        buf[12] = ((HEADER_LEN / 4) as u8) << 4;
+        buf[13] = self.flags.as_byte();
+        buf[14..16].copy_from_slice(&self.window_size.to_be_bytes());
+        buf[16..18].copy_from_slice(&self.checksum.to_be_bytes());
+        buf[18..20].copy_from_slice(&self.urgent_pointer.to_be_bytes());
+        buf[20..20 + self.data.len()].copy_from_slice(&self.data[..]);
+    }
+
+    fn size(&self) -> usize {
+        HEADER_LEN + self.data.len()
+    }
+}
+
+/// A TCP connection's state (RFC793 Section 3.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait,
+    CloseWait,
+    TimeWait,
+}
+
+/// The largest amount of data placed in a single outgoing segment. There's
+/// no options parsing in this minimal implementation, so this is just a
+/// conservative default MSS rather than one negotiated with the peer.
+const MAX_SEGMENT_SIZE: usize = 536;
+
+/// One TCP connection's send/receive sequence-space state machine.
+///
+/// This builds and consumes `TcpPacket`s but doesn't itself touch the
+/// network device, and doesn't hold received data either - callers own
+/// moving segments to/from the wire and copying in-order payload bytes into
+/// their own socket buffer, the same split `DhcpClient` uses between "what
+/// the protocol state machine does" and "how it's wired into the network
+/// stack".
+#[derive(Debug)]
+pub struct TcpConnection {
+    state: TcpState,
+    source_port: u16,
+    dest_port: u16,
+    send_next: TcpSeqNumber,
+    send_unacknowledged: TcpSeqNumber,
+    receive_next: TcpSeqNumber,
+    /// The peer's last-advertised receive window, used to cap how much
+    /// unacknowledged data we're allowed to have in flight.
+    send_window: u16,
+    /// Segments sent but not yet acknowledged, oldest first, kept around so
+    /// they can be retransmitted. Pruned as ACKs arrive in `ack`; its oldest
+    /// entry is resent, and kept, by `retransmit` once `retransmit_deadline`
+    /// comes due.
+    retransmit_queue: Vec<(TcpSeqNumber, Vec<u8>)>,
+    /// Absolute `rdtsc` reading at which `retransmit` should next resend the
+    /// oldest entry in `retransmit_queue`. Armed by `send_data` while the
+    /// queue is empty, pushed back by `ack` and `retransmit` as long as
+    /// something's still outstanding, and cleared once the queue drains -
+    /// the same armed-while-pending, cleared-on-completion shape as
+    /// `Socket::connect_deadline`.
+    retransmit_deadline: Option<u64>,
+}
+
+impl TcpConnection {
+    /// Start a new connection in LISTEN, waiting for an incoming SYN.
+    pub fn listen(source_port: u16) -> TcpConnection {
+        let initial_seq = TcpSeqNumber::from_tsc();
+        TcpConnection {
+            state: TcpState::Listen,
+            source_port,
+            dest_port: 0,
+            send_next: initial_seq,
+            send_unacknowledged: initial_seq,
+            receive_next: TcpSeqNumber::new(0),
+            send_window: 0,
+            retransmit_queue: Vec::new(),
+            retransmit_deadline: None,
+        }
+    }
+
+    /// Start a new connection with an active open, sending a SYN of our own
+    /// (see `syn`) rather than waiting for one.
+    pub fn connect(source_port: u16, dest_port: u16) -> TcpConnection {
+        let initial_seq = TcpSeqNumber::from_tsc();
+        TcpConnection {
+            state: TcpState::SynSent,
+            source_port,
+            dest_port,
+            send_next: initial_seq,
+            send_unacknowledged: initial_seq,
+            receive_next: TcpSeqNumber::new(0),
+            send_window: 0,
+            retransmit_queue: Vec::new(),
+            retransmit_deadline: None,
+        }
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.state
+    }
+
+    /// The oldest byte we've sent that the peer hasn't acknowledged yet.
+    pub fn send_unacknowledged(&self) -> TcpSeqNumber {
+        self.send_unacknowledged
+    }
+
+    /// The next byte we expect to receive - a segment starting anywhere else
+    /// is a duplicate or out-of-order and its data isn't accepted by
+    /// `handle`.
+    pub fn receive_next(&self) -> TcpSeqNumber {
+        self.receive_next
+    }
+
+    /// Build this connection's initial SYN segment, for an active open
+    /// started with `connect`.
+    ///
+    /// Stamped with `send_unacknowledged` (the ISS), not `send_next` -
+    /// `poll` calls this again to retransmit the same SYN for as long as
+    /// the handshake is pending, and a retransmission must carry the exact
+    /// sequence number it did the first time. The SYN itself consumes one
+    /// octet of sequence space (RFC793 Section 3.3), so `send_next` is
+    /// advanced past it here, but only the first time this is called -
+    /// `send_next == send_unacknowledged` is this connection's signal that
+    /// the SYN hasn't been accounted for yet, since nothing else advances
+    /// either before the handshake completes.
+    pub fn syn(&mut self, pseudo_header: PseudoHeader) -> TcpPacket {
+        let segment = TcpPacket::new(
+            self.source_port,
+            self.dest_port,
+            self.send_unacknowledged,
+            self.receive_next,
+            TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            crate::packet_buffer::BUFFER_SIZE as u16,
+            vec![],
+            pseudo_header,
+            ChecksumCapabilities::default(),
+        );
+
+        if self.send_next == self.send_unacknowledged {
+            self.send_next = self.send_next.wrapping_add(1);
+        }
+
+        segment
+    }
+
+    /// Advance `send_unacknowledged`/`send_window` from a received ACK, and
+    /// drop any now-fully-acknowledged segments from `retransmit_queue`.
+    ///
+    /// Stale or duplicate ACKs (acknowledging nothing new) are ignored
+    /// rather than moving `send_unacknowledged` backwards. Since this is
+    /// progress, `retransmit_deadline` is pushed back if anything's still
+    /// outstanding, or cleared if the queue just drained.
+    fn ack(&mut self, ack_number: TcpSeqNumber, window: u16) {
+        if ack_number.wrapping_sub(self.send_unacknowledged) <= 0 {
+            return;
+        }
+
+        self.send_unacknowledged = ack_number;
+        self.send_window = window;
+        self.retransmit_queue
+            .retain(|(seq, data)| seq.wrapping_add(data.len() as u32) > self.send_unacknowledged);
+
+        self.retransmit_deadline = if self.retransmit_queue.is_empty() {
+            None
+        } else {
+            Some(rdtsc() + RETRANSMIT_TIMEOUT_SECS * CPU_FREQ_MHZ * 1_000_000)
+        };
+    }
+
+    /// How many bytes of new data we're currently allowed to send, given the
+    /// peer's last-advertised window and what's already in flight.
+    ///
+    /// Uses `wrapping_sub`/`saturating_sub` throughout so a window that
+    /// shrank since it was last advertised, or a send sequence number that's
+    /// wrapped, can't underflow this into a huge bogus value.
+    fn usable_window(&self) -> u32 {
+        let in_flight = self.send_next.wrapping_sub(self.send_unacknowledged).max(0) as u32;
+        (self.send_window as u32).saturating_sub(in_flight)
+    }
+
+    /// Segment `data`, capped to the current usable window and to
+    /// `MAX_SEGMENT_SIZE` per segment, stamping each with the next send
+    /// sequence number and the ACK/PSH control bits and queuing it for
+    /// retransmission.
+    ///
+    /// Returns the segments to send, in order; if the window is currently
+    /// full this may be empty.
+    pub fn send_data(&mut self, data: &[u8], pseudo_header: PseudoHeader) -> Vec<TcpPacket> {
+        let sendable = data.len().min(self.usable_window() as usize);
+        let mut segments = Vec::new();
+
+        for chunk in data[..sendable].chunks(MAX_SEGMENT_SIZE) {
+            let segment = self.make_segment(
+                TcpFlags {
+                    ack: true,
+                    psh: true,
+                    ..Default::default()
+                },
+                chunk,
+                pseudo_header,
+            );
+            self.retransmit_queue
+                .push((self.send_next, chunk.to_vec()));
+            self.send_next = self.send_next.wrapping_add(chunk.len() as u32);
+            segments.push(segment);
+        }
+
+        if !segments.is_empty() && self.retransmit_deadline.is_none() {
+            self.retransmit_deadline =
+                Some(rdtsc() + RETRANSMIT_TIMEOUT_SECS * CPU_FREQ_MHZ * 1_000_000);
+        }
+
+        segments
+    }
+
+    /// The `rdtsc` reading at which `retransmit` should next be called, if
+    /// there's currently unacknowledged data outstanding.
+    pub fn retransmit_deadline(&self) -> Option<u64> {
+        self.retransmit_deadline
+    }
+
+    /// Resend the oldest entry in `retransmit_queue`, stamped with this
+    /// connection's *current* ack number and window rather than whatever
+    /// was current when it was first sent, and push `retransmit_deadline`
+    /// back out.
+    ///
+    /// Returns `None` (disarming the timer) if the queue is empty - which
+    /// only happens if `poll` raced an `ack` that just drained it.
+    pub fn retransmit(&mut self, pseudo_header: PseudoHeader) -> Option<TcpPacket> {
+        let (seq, data) = match self.retransmit_queue.first() {
+            Some(x) => x,
+            None => {
+                self.retransmit_deadline = None;
+                return None;
+            }
+        };
+
+        let segment = TcpPacket::new(
+            self.source_port,
+            self.dest_port,
+            *seq,
+            self.receive_next,
+            TcpFlags {
+                ack: true,
+                psh: true,
+                ..Default::default()
+            },
+            crate::packet_buffer::BUFFER_SIZE as u16,
+            data.clone(),
+            pseudo_header,
+            ChecksumCapabilities::default(),
+        );
+        self.retransmit_deadline = Some(rdtsc() + RETRANSMIT_TIMEOUT_SECS * CPU_FREQ_MHZ * 1_000_000);
+        Some(segment)
+    }
+
+    /// Feed one received segment through the state machine, returning a
+    /// segment to send back, if any.
+    pub fn handle(&mut self, segment: &TcpPacket, pseudo_header: PseudoHeader) -> Option<TcpPacket> {
+        match self.state {
+            TcpState::Listen => {
+                if !segment.flags.syn {
+                    return None;
+                }
+                self.dest_port = segment.source_port;
+                self.receive_next = segment.seq_number.wrapping_add(1);
+                self.state = TcpState::SynReceived;
+                let syn_ack = self.make_segment(
+                    TcpFlags {
+                        syn: true,
+                        ack: true,
+                        ..Default::default()
+                    },
+                    &[],
+                    pseudo_header,
+                );
+                // The SYN-ACK's own SYN consumes one octet of sequence
+                // space (RFC793 Section 3.3), same as the active-open SYN
+                // `syn()` accounts for - this only runs once per accepted
+                // connection (the `Listen` state is left for good right
+                // above), so there's no retransmission case to guard
+                // against here.
+                self.send_next = self.send_next.wrapping_add(1);
+                Some(syn_ack)
+            }
+            TcpState::SynSent => {
+                if !(segment.flags.syn && segment.flags.ack) {
+                    return None;
+                }
+                self.receive_next = segment.seq_number.wrapping_add(1);
+                self.ack(segment.ack_number, segment.window_size);
+                self.state = TcpState::Established;
+                Some(self.make_segment(
+                    TcpFlags {
+                        ack: true,
+                        ..Default::default()
+                    },
+                    &[],
+                    pseudo_header,
+                ))
+            }
+            TcpState::SynReceived => {
+                if segment.flags.ack {
+                    self.ack(segment.ack_number, segment.window_size);
+                    self.state = TcpState::Established;
+                }
+                None
+            }
+            TcpState::Established => {
+                if segment.flags.ack {
+                    self.ack(segment.ack_number, segment.window_size);
+                }
+
+                // A retransmitted duplicate, or a segment that arrived out
+                // of order, doesn't start where we expect - RFC793 Section
+                // 3.9 says to drop it rather than accept its data, and to
+                // re-ACK the sequence number we actually expect so the peer
+                // knows to retransmit from there.
+                if segment.seq_number.wrapping_sub(self.receive_next) != 0 {
+                    if segment.data.is_empty() && !segment.flags.fin {
+                        return None;
+                    }
+                    return Some(self.make_segment(
+                        TcpFlags {
+                            ack: true,
+                            ..Default::default()
+                        },
+                        &[],
+                        pseudo_header,
+                    ));
+                }
+
+                if segment.flags.fin {
+                    self.receive_next = self.receive_next.wrapping_add(1);
+                    self.state = TcpState::CloseWait;
+                    return Some(self.make_segment(
+                        TcpFlags {
+                            ack: true,
+                            ..Default::default()
+                        },
+                        &[],
+                        pseudo_header,
+                    ));
+                }
+
+                if segment.data.is_empty() {
+                    return None;
+                }
+
+                self.receive_next = self
+                    .receive_next
+                    .wrapping_add(segment.data.len() as u32);
+                Some(self.make_segment(
+                    TcpFlags {
+                        ack: true,
+                        ..Default::default()
+                    },
+                    &[],
+                    pseudo_header,
+                ))
+            }
+            TcpState::CloseWait | TcpState::FinWait | TcpState::TimeWait | TcpState::Closed => None,
+        }
+    }
+
+    fn make_segment(&self, flags: TcpFlags, data: &[u8], pseudo_header: PseudoHeader) -> TcpPacket {
+        TcpPacket::new(
+            self.source_port,
+            self.dest_port,
+            self.send_next,
+            self.receive_next,
+            flags,
+            crate::packet_buffer::BUFFER_SIZE as u16,
+            data.to_vec(),
+            pseudo_header,
+            ChecksumCapabilities::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::ip::Ipv4Addr;
+
+    fn pseudo_header() -> PseudoHeader {
+        PseudoHeader::Ipv4 {
+            source: Ipv4Addr::new(10, 0, 0, 2),
+            destination: Ipv4Addr::new(10, 0, 0, 1),
+        }
+    }
+
+    fn segment(seq: u32, ack: u32, flags: TcpFlags, data: Vec<u8>) -> TcpPacket {
+        TcpPacket::new(
+            2000,
+            100,
+            TcpSeqNumber::new(seq),
+            TcpSeqNumber::new(ack),
+            flags,
+            1000,
+            data,
+            pseudo_header(),
+            ChecksumCapabilities::default(),
+        )
+    }
+
+    /// Drives a fresh `listen()`ed connection through the handshake into
+    /// `Established`, with the peer's initial sequence number fixed at 1000
+    /// so the rest of the test can reason about exact sequence numbers.
+    fn established() -> TcpConnection {
+        let mut connection = TcpConnection::listen(100);
+
+        let syn = segment(
+            1000,
+            0,
+            TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            vec![],
+        );
+        let syn_ack = connection
+            .handle(&syn, pseudo_header())
+            .expect("SYN gets a SYN-ACK reply");
+        assert_eq!(connection.state(), TcpState::SynReceived);
+        assert_eq!(connection.receive_next(), TcpSeqNumber::new(1001));
+
+        let ack = segment(
+            1001,
+            syn_ack.seq_number().wrapping_add(1).value(),
+            TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            vec![],
+        );
+        assert!(connection.handle(&ack, pseudo_header()).is_none());
+        assert_eq!(connection.state(), TcpState::Established);
+
+        connection
+    }
+
+    /// An in-order data segment is appended to `receive_next` and acked.
+    #[test]
+    fn in_order_segment_advances_receive_next() {
+        let mut connection = established();
+
+        let data = segment(
+            1001,
+            0,
+            TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            vec![1, 2, 3],
+        );
+        let reply = connection
+            .handle(&data, pseudo_header())
+            .expect("data segment gets acked");
+
+        assert_eq!(connection.receive_next(), TcpSeqNumber::new(1004));
+        assert_eq!(reply.ack_number(), TcpSeqNumber::new(1004));
+    }
+
+    /// A retransmitted duplicate of a segment already folded into
+    /// `receive_next` must not be accepted a second time - this is exactly
+    /// what `fault::FaultInjectingDevice` is built to produce on a real link.
+    #[test]
+    fn duplicate_segment_is_dropped_not_reapplied() {
+        let mut connection = established();
+
+        let data = segment(
+            1001,
+            0,
+            TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            vec![1, 2, 3],
+        );
+        connection
+            .handle(&data, pseudo_header())
+            .expect("first delivery is accepted");
+        assert_eq!(connection.receive_next(), TcpSeqNumber::new(1004));
+
+        let reply = connection
+            .handle(&data, pseudo_header())
+            .expect("duplicate still gets an ack-only reply");
+
+        // `receive_next` must not move again, and the reply is a bare ack of
+        // what we already have, not a second acceptance of the payload.
+        assert_eq!(connection.receive_next(), TcpSeqNumber::new(1004));
+        assert_eq!(reply.ack_number(), TcpSeqNumber::new(1004));
+        assert!(reply.data().is_empty());
+    }
+
+    /// A segment that starts ahead of `receive_next` (out of order, e.g. a
+    /// reordered later segment) is likewise dropped rather than folded in.
+    #[test]
+    fn out_of_order_segment_is_dropped() {
+        let mut connection = established();
+
+        let data = segment(
+            1010,
+            0,
+            TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            vec![1, 2, 3],
+        );
+        let reply = connection
+            .handle(&data, pseudo_header())
+            .expect("out-of-order segment still gets an ack-only reply");
+
+        assert_eq!(connection.receive_next(), TcpSeqNumber::new(1001));
+        assert_eq!(reply.ack_number(), TcpSeqNumber::new(1001));
+        assert!(reply.data().is_empty());
+    }
+
+    /// `send_data` arms `retransmit_deadline`, and a full ack of everything
+    /// outstanding disarms it again - `retransmit` has nothing left to do
+    /// once the peer's caught up.
+    #[test]
+    fn retransmit_deadline_tracks_outstanding_data() {
+        let mut connection = established();
+
+        assert!(connection.retransmit_deadline().is_none());
+
+        let segments = connection.send_data(&[1, 2, 3], pseudo_header());
+        assert_eq!(segments.len(), 1);
+        assert!(connection.retransmit_deadline().is_some());
+
+        let ack = segment(
+            1,
+            segments[0].seq_number().wrapping_add(3).value(),
+            TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            vec![],
+        );
+        connection.handle(&ack, pseudo_header());
+
+        assert!(connection.retransmit_deadline().is_none());
+    }
+
+    /// Once armed, `retransmit` resends the oldest unacknowledged segment
+    /// unchanged (same sequence number and payload) rather than whatever
+    /// `send_next` has moved on to - this is the path chunk2-5's
+    /// `FaultInjectingDevice` exists to exercise.
+    #[test]
+    fn retransmit_resends_oldest_unacked_segment() {
+        let mut connection = established();
+
+        let first = connection.send_data(&[1, 2, 3], pseudo_header());
+        let second = connection.send_data(&[4, 5], pseudo_header());
+        assert_eq!(first.len(), 1);
+        assert_eq!(second.len(), 1);
+
+        let resent = connection
+            .retransmit(pseudo_header())
+            .expect("unacked data is queued for retransmission");
+
+        assert_eq!(resent.seq_number(), first[0].seq_number());
+        assert_eq!(resent.data(), first[0].data());
+        assert!(connection.retransmit_deadline().is_some());
+    }
+
+    /// The SYN-ACK's own SYN consumes one octet of sequence space
+    /// (RFC793 Section 3.3), so the first data segment sent after the
+    /// handshake completes must start one past it, not reuse the SYN-ACK's
+    /// own (already-acked) sequence number.
+    #[test]
+    fn first_data_segment_seq_follows_syn_ack() {
+        let mut connection = TcpConnection::listen(100);
+
+        let syn = segment(
+            1000,
+            0,
+            TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            vec![],
+        );
+        let syn_ack = connection
+            .handle(&syn, pseudo_header())
+            .expect("SYN gets a SYN-ACK reply");
+
+        let ack = segment(
+            1001,
+            syn_ack.seq_number().wrapping_add(1).value(),
+            TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            vec![],
+        );
+        connection.handle(&ack, pseudo_header());
+        assert_eq!(connection.state(), TcpState::Established);
+
+        let data = connection.send_data(&[1, 2, 3], pseudo_header());
+        assert_eq!(data[0].seq_number(), syn_ack.seq_number().wrapping_add(1));
+    }
+}
@@ -0,0 +1,236 @@
+use alloc::format;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arp::ArpPacket;
+use crate::ethernet::{EthernetFrame, Ethertype};
+use crate::icmp::IcmpPacket;
+use crate::ip::{Ipv4Packet, Protocol};
+use crate::kernel::cprint;
+use crate::net::NetworkDevice;
+use crate::packet_buffer::{FromBuffer, PacketBuffer};
+use crate::udp::UdpPacket;
+
+/// Whether `TracingDevice` pretty-prints decoded frames to the serial
+/// console. Mirrors `e1000`'s `TRACE_MASK`: a module-level flag rather than
+/// a field on the device, since by the time a driver is wrapped and boxed
+/// up behind `dyn NetworkDevice` there's no call site left holding the
+/// concrete type to toggle a field on.
+static PRINT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable decoded-frame pretty-printing for every `TracingDevice`
+/// in the system. Capture to the pcap ring is toggled separately, via
+/// `pcap::CAPTURE`.
+pub fn set_print_enabled(enabled: bool) {
+    PRINT_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn print_enabled() -> bool {
+    PRINT_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Wraps a `NetworkDevice`, sitting between the driver and the protocol
+/// stack so every frame that passes through `send`/`recv` can be decoded and
+/// pretty-printed over the serial console, toggleable at runtime without
+/// touching any `send`/`recv` call site.
+///
+/// Stacks with `pcap::PcapDevice` and `fault::FaultInjectingDevice` - each
+/// wrapper only touches what it's responsible for and forwards everything
+/// else to `inner`.
+pub struct TracingDevice<D: NetworkDevice> {
+    inner: D,
+}
+
+impl<D: NetworkDevice> TracingDevice<D> {
+    pub fn new(inner: D) -> TracingDevice<D> {
+        TracingDevice { inner }
+    }
+
+    /// `len` is the number of valid bytes at `buf.as_ptr()`; callers pass
+    /// `buf.len()` for a buffer they just finished serializing (TX) or
+    /// `buf.remaining().len()` for one fresh off the wire (RX, where
+    /// `len()` still reads its unadvanced parse offset).
+    fn trace(&self, direction: &str, buf: &PacketBuffer, len: usize) {
+        if !print_enabled() {
+            return;
+        }
+        let frame = unsafe { core::slice::from_raw_parts(buf.as_ptr(), len) };
+        print_frame(direction, frame);
+    }
+}
+
+impl<D: NetworkDevice> NetworkDevice for TracingDevice<D> {
+    fn hardware_address(&self) -> crate::ethernet::EthernetAddress {
+        self.inner.hardware_address()
+    }
+
+    fn protocol_address(&self) -> crate::ip::Ipv4Addr {
+        self.inner.protocol_address()
+    }
+
+    fn set_protocol_address(&mut self, protocol_address: crate::ip::Ipv4Addr) {
+        self.inner.set_protocol_address(protocol_address);
+    }
+
+    fn clear_interrupts(&mut self) {
+        self.inner.clear_interrupts();
+    }
+
+    /// Unlike `send`, there's no complete frame here yet to pretty-print -
+    /// the caller serializes into the buffer we return after this returns.
+    /// Tracing instead happens when that buffer is dropped: wrap `inner`'s
+    /// loaned buffer in one of our own, over the same memory, whose
+    /// `reclaim` traces the now-fully-written frame before handing off to
+    /// `inner`'s `reclaim` to actually transmit it.
+    fn transmit(&mut self, len: usize) -> Option<PacketBuffer> {
+        let inner_buf = self.inner.transmit(len)?;
+        let addr = inner_buf.as_ptr() as usize;
+        let mut inner_buf = Some(inner_buf);
+        Some(unsafe {
+            PacketBuffer::new_loaned(addr as *mut u8, len, move || {
+                if print_enabled() {
+                    let frame = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+                    print_frame("tx", frame);
+                }
+                inner_buf.take();
+            })
+        })
+    }
+
+    fn send(&mut self, buf: PacketBuffer) -> Result<(), ()> {
+        self.trace("tx", &buf, buf.len());
+        self.inner.send(buf)
+    }
+
+    fn recv(&mut self) -> Option<PacketBuffer> {
+        let buf = self.inner.recv()?;
+        self.trace("rx", &buf, buf.remaining().len());
+        Some(buf)
+    }
+}
+
+/// Forward the trait through a box, so a `TracingDevice` can wrap (or be
+/// wrapped in) the same `Box<dyn NetworkDevice>` the rest of the stack
+/// passes around.
+impl<T: NetworkDevice + ?Sized> NetworkDevice for alloc::boxed::Box<T> {
+    fn hardware_address(&self) -> crate::ethernet::EthernetAddress {
+        (**self).hardware_address()
+    }
+
+    fn protocol_address(&self) -> crate::ip::Ipv4Addr {
+        (**self).protocol_address()
+    }
+
+    fn set_protocol_address(&mut self, protocol_address: crate::ip::Ipv4Addr) {
+        (**self).set_protocol_address(protocol_address);
+    }
+
+    fn clear_interrupts(&mut self) {
+        (**self).clear_interrupts();
+    }
+
+    fn transmit(&mut self, len: usize) -> Option<PacketBuffer> {
+        (**self).transmit(len)
+    }
+
+    fn send(&mut self, buf: PacketBuffer) -> Result<(), ()> {
+        (**self).send(buf)
+    }
+
+    fn recv(&mut self) -> Option<PacketBuffer> {
+        (**self).recv()
+    }
+}
+
+/// Decode and pretty-print one frame's headers to the serial console:
+/// `EthernetFrame` -> `Ipv4Packet` -> `UdpPacket`/`IcmpPacket`, one line per
+/// layer, each written out via the existing `cprint` FFI.
+fn print_frame(direction: &str, frame: &[u8]) {
+    if frame.len() < 14 {
+        return;
+    }
+
+    let ethernet = EthernetFrame::from_slice(frame);
+    unsafe {
+        cprint(
+            format!(
+                "trace: {} ethernet {:?} -> {:?} type {:?}\0",
+                direction, ethernet.source, ethernet.destination, ethernet.ethertype
+            )
+            .as_ptr(),
+        );
+    }
+
+    if matches!(ethernet.ethertype, Ethertype::ARP) {
+        if let Ok(arp) = ArpPacket::from_buffer(&frame[14..]) {
+            unsafe {
+                cprint(
+                    format!(
+                        "trace: {} arp {:?} {:?} -> {:?}\0",
+                        direction, arp.oper, arp.spa, arp.tpa
+                    )
+                    .as_ptr(),
+                );
+            }
+        }
+        return;
+    }
+
+    if !matches!(ethernet.ethertype, Ethertype::IPV4) {
+        return;
+    }
+
+    // `Ipv4Packet` doesn't parse options, so its header is always 20 bytes.
+    const IPV4_HEADER_LEN: usize = 20;
+    if frame.len() < 14 + IPV4_HEADER_LEN {
+        return;
+    }
+
+    let ip = Ipv4Packet::from_slice(&frame[14..]);
+    unsafe {
+        cprint(
+            format!(
+                "trace: {} ip {:?} -> {:?} protocol {:?} len {}\0",
+                direction,
+                ip.source(),
+                ip.destination(),
+                ip.protocol(),
+                ip.total_length()
+            )
+            .as_ptr(),
+        );
+    }
+
+    let payload = &frame[14 + IPV4_HEADER_LEN..];
+    match ip.protocol() {
+        Protocol::UDP => {
+            if let Ok(udp) = UdpPacket::from_buffer(payload) {
+                unsafe {
+                    cprint(
+                        format!(
+                            "trace: {} udp {} -> {} len {}\0",
+                            direction,
+                            udp.source_port(),
+                            udp.dest_port(),
+                            udp.data().len()
+                        )
+                        .as_ptr(),
+                    );
+                }
+            }
+        }
+        Protocol::ICMP => {
+            if let Ok(icmp) = IcmpPacket::from_buffer(payload) {
+                unsafe {
+                    cprint(
+                        format!(
+                            "trace: {} icmp type {} code {}\0",
+                            direction, icmp.icmp_type, icmp.code
+                        )
+                        .as_ptr(),
+                    );
+                }
+            }
+        }
+        _ => (),
+    }
+}
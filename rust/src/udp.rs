@@ -1,7 +1,104 @@
+use alloc::vec;
 use alloc::vec::Vec;
 
+use crate::ip::Ipv4Addr;
 use crate::packet_buffer::{FromBuffer, PacketBuffer, ToBuffer};
 
+/// The protocol number UDP uses as the pseudo-header's "next header"/
+/// "protocol" field (RFC768).
+const PROTOCOL_UDP: u8 = 0x11;
+
+/// The address pair a transport-layer checksum is computed over (RFC768).
+/// An enum so a future second IP version can add its own variant without
+/// changing how callers build or consume one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PseudoHeader {
+    Ipv4 {
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    },
+}
+
+impl PseudoHeader {
+    /// Sum the pseudo-header's 16-bit words (source address, destination
+    /// address, upper-layer length and next-header/protocol) ahead of
+    /// folding, for `upper_layer_length` bytes of protocol `protocol`.
+    ///
+    /// Visible to other transport modules (e.g. `tcp`) that need the same
+    /// pseudo-header sum over their own header/payload.
+    pub(crate) fn sum(&self, upper_layer_length: u32, protocol: u8) -> u32 {
+        let mut sum = match self {
+            PseudoHeader::Ipv4 {
+                source,
+                destination,
+            } => sum_bytes(&source.as_bytes()) + sum_bytes(&destination.as_bytes()),
+        };
+        sum += (upper_layer_length >> 16) & 0xffff;
+        sum += upper_layer_length & 0xffff;
+        sum += protocol as u32;
+        sum
+    }
+}
+
+/// Sum the 16-bit big-endian words of an even-length byte slice, e.g. one
+/// half of a pseudo-header's address fields.
+fn sum_bytes(buf: &[u8]) -> u32 {
+    let mut sum = 0u32;
+    for chunk in buf.chunks_exact(2) {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    sum
+}
+
+/// Whether a protocol's checksum is computed/verified in software on the
+/// transmit path, the receive path, both, or neither - "neither" covering a
+/// lower layer (e.g. e1000 TCP/IP context descriptor offload) already having
+/// done the work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Both,
+    Tx,
+    Rx,
+    None,
+}
+
+impl Checksum {
+    // Visible to other transport modules (e.g. `tcp`) that hold their own
+    // `ChecksumCapabilities` field.
+    pub(crate) fn tx(&self) -> bool {
+        matches!(self, Checksum::Both | Checksum::Tx)
+    }
+
+    pub(crate) fn rx(&self) -> bool {
+        matches!(self, Checksum::Both | Checksum::Rx)
+    }
+}
+
+/// Per-protocol checksum handling. One field per protocol that has a
+/// software-computable checksum; grows as more protocols need it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumCapabilities {
+    pub udp: Checksum,
+    pub tcp: Checksum,
+}
+
+impl Default for ChecksumCapabilities {
+    /// Compute and verify checksums in software on both paths.
+    fn default() -> Self {
+        ChecksumCapabilities {
+            udp: Checksum::Both,
+            tcp: Checksum::Both,
+        }
+    }
+}
+
+/// Errors surfaced while parsing or verifying a UDP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpError {
+    /// The packet's checksum didn't match the pseudo-header, header and payload.
+    Checksum,
+}
+
 /// Represents a UDP packet header.
 #[derive(Debug, Clone)]
 pub struct UdpPacket {
@@ -13,13 +110,77 @@ pub struct UdpPacket {
 }
 
 impl UdpPacket {
-    pub fn new(source_port: u16, dest_port: u16, data: Vec<u8>) -> Self {
-        UdpPacket {
+    /// Build a new UDP packet, computing a real checksum over
+    /// `pseudo_header` and the header/payload unless `capabilities` says to
+    /// skip it.
+    pub fn new(
+        source_port: u16,
+        dest_port: u16,
+        data: Vec<u8>,
+        pseudo_header: PseudoHeader,
+        capabilities: ChecksumCapabilities,
+    ) -> Self {
+        let mut packet = UdpPacket {
             source_port: source_port,
             dest_port: dest_port,
             len: (data.len() + 8) as u16,
             checksum: 0,
             data: data,
+        };
+        if capabilities.udp.tx() {
+            packet.checksum = packet.compute_checksum(pseudo_header);
+        }
+        packet
+    }
+
+    /// Verify this packet's checksum against the pseudo-header that carried
+    /// it, unless `capabilities` says to skip verification or the sender
+    /// sent an all-zero checksum (meaning none was computed, as RFC768
+    /// permits over IPv4).
+    pub fn verify_checksum(
+        &self,
+        pseudo_header: PseudoHeader,
+        capabilities: ChecksumCapabilities,
+    ) -> Result<(), UdpError> {
+        if !capabilities.udp.rx() || self.checksum == 0 {
+            return Ok(());
+        }
+
+        if self.compute_checksum(pseudo_header) != self.checksum {
+            return Err(UdpError::Checksum);
+        }
+
+        Ok(())
+    }
+
+    /// Recompute what this packet's checksum should be, given the pseudo-
+    /// header it was (or will be) carried in.
+    ///
+    /// The checksum is the 16-bit one's-complement sum over the pseudo-
+    /// header followed by the UDP header (with the checksum field zeroed)
+    /// and the payload, padded with a zero byte if that's of odd length.
+    fn compute_checksum(&self, pseudo_header: PseudoHeader) -> u16 {
+        let mut bytes = vec![0u8; ToBuffer::size(self)];
+        self.to_buffer(&mut bytes);
+        bytes[6..8].copy_from_slice(&0u16.to_be_bytes());
+
+        let mut sum = pseudo_header.sum(bytes.len() as u32, PROTOCOL_UDP);
+
+        let mut chunks = bytes.chunks_exact(2);
+        for chunk in &mut chunks {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+        if let [last] = chunks.remainder() {
+            sum += (*last as u32) << 8;
+        }
+
+        let check = (sum >> 16) + (sum & 0xffff);
+        let check = (check >> 16) + (check & 0xffff);
+        let checksum = !(check as u16);
+        if checksum == 0 {
+            0xFFFF
+        } else {
+            checksum
         }
     }
 
@@ -45,6 +206,10 @@ impl UdpPacket {
         })
     }
 
+    pub fn source_port(&self) -> u16 {
+        return self.source_port;
+    }
+
     pub fn dest_port(&self) -> u16 {
         return self.dest_port;
     }